@@ -1,80 +1,283 @@
+use crate::bail_code;
+use crate::error::{ErrorCode, WithCode};
+use crate::img::{
+    CancelToken, DecodeOptions, FrameSelection, Job, Progress, StageBuilder, Step, ToneMapping,
+    convert_atlas, convert_jk_file, get_preset, panic_message, run_batch_with_cancel,
+    save_dds_file,
+};
 use anyhow::{Context, Result};
+use directxtex::DXGI_FORMAT;
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::mem::{MaybeUninit, offset_of, size_of};
+use std::path::Path;
 use std::ptr;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
 
-pub const SUCCESS: i32 = 0;
-pub const FAILURE: i32 = 1;
+pub const SUCCESS: i32 = ErrorCode::Success as i32;
+pub const PANIC: i32 = ErrorCode::Panic as i32;
 
 #[macro_export]
 macro_rules! check_null_ptr {
     ($ptr:expr) => {
         if $ptr.is_null() {
-            anyhow::bail!("NULL received for {}", stringify!($ptr));
+            $crate::bail_code!(
+                $crate::error::ErrorCode::InvalidArgument,
+                "NULL received for {}",
+                stringify!($ptr)
+            );
         }
     };
 }
 
+/// Exports `$func_name` taking UTF-16 strings and `${func_name}_utf8` taking UTF-8 strings.
+/// String parameters and the error buffer are declared with the `Char` type, which is `u16`
+/// or `c_char` depending on the export being generated.
 #[macro_export]
 macro_rules! api {
-    ($func_name:ident($($param:ident: $type:ty),*) $body:block) => {
-        #[unsafe(no_mangle)]
-        pub extern "C" fn $func_name(
-            $($param: $type,)*
-            error_buffer: *mut u16,
-            error_buffer_size: std::os::raw::c_int,
-        ) -> std::os::raw::c_int {
-            let result = (|| -> anyhow::Result<()> {
-                $body
-            })();
-
-            match result {
-                Ok(_) => SUCCESS,
-                Err(err) => set_error_msg(error_buffer, error_buffer_size, &err),
+    (@export $func_name:ident, $char:ty, ($($param:ident: $type:ty),*) $body:block) => {
+        const _: () = {
+            type Char = $char;
+
+            #[unsafe(no_mangle)]
+            pub extern "C" fn $func_name(
+                $($param: $type,)*
+                error_buffer: *mut Char,
+                error_buffer_size: std::os::raw::c_int,
+            ) -> std::os::raw::c_int {
+                clear_last_error();
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+                    || -> anyhow::Result<()> { $body },
+                ));
+
+                match result {
+                    Ok(Ok(_)) => SUCCESS,
+                    Ok(Err(err)) => report_error(error_buffer, error_buffer_size, &err),
+                    Err(payload) => report_panic(error_buffer, error_buffer_size, payload),
+                }
             }
+        };
+    };
+    ($func_name:ident($($param:ident: $type:ty),*) $body:block) => {
+        $crate::api!(@export $func_name, u16, ($($param: $type),*) $body);
+        paste::paste! {
+            $crate::api!(
+                @export [<$func_name _utf8>],
+                std::ffi::c_char,
+                ($($param: $type),*) $body
+            );
         }
     };
 }
 
-pub fn set_error_msg(
-    error_buffer: *mut u16,
+/// Character type of the strings crossing the FFI boundary.
+pub trait FfiChar: Copy + PartialEq + 'static {
+    const NUL: Self;
+    const ENCODING: &'static str;
+
+    fn decode(units: &[Self]) -> Option<String>;
+    fn encode(text: &str) -> Vec<Self>;
+    /// Largest length `<= max_len` that does not split a code point of `units`.
+    fn floor_boundary(units: &[Self], max_len: usize) -> usize;
+}
+
+impl FfiChar for u16 {
+    const NUL: Self = 0;
+    const ENCODING: &'static str = "UTF-16";
+
+    fn decode(units: &[Self]) -> Option<String> {
+        String::from_utf16(units).ok()
+    }
+
+    fn encode(text: &str) -> Vec<Self> {
+        text.encode_utf16().collect()
+    }
+
+    fn floor_boundary(units: &[Self], max_len: usize) -> usize {
+        match units.get(max_len) {
+            Some(0xDC00..=0xDFFF) if max_len > 0 => max_len - 1,
+            _ => max_len.min(units.len()),
+        }
+    }
+}
+
+impl FfiChar for c_char {
+    const NUL: Self = 0;
+    const ENCODING: &'static str = "UTF-8";
+
+    fn decode(units: &[Self]) -> Option<String> {
+        let bytes = units.iter().map(|&b| b as u8).collect();
+        String::from_utf8(bytes).ok()
+    }
+
+    fn encode(text: &str) -> Vec<Self> {
+        text.bytes().map(|b| b as c_char).collect()
+    }
+
+    fn floor_boundary(units: &[Self], max_len: usize) -> usize {
+        let mut len = max_len.min(units.len());
+        while len > 0 && len < units.len() && (units[len] as u8) & 0xC0 == 0x80 {
+            len -= 1;
+        }
+        len
+    }
+}
+
+/// Writes `err` to the error buffer and returns its `ErrorCode`. A panic while formatting or
+/// copying the message is caught and turned into `PANIC` instead of unwinding into the caller.
+pub fn report_error<C: FfiChar>(
+    error_buffer: *mut C,
     error_buffer_size: i32,
-    err: impl std::fmt::Display,
+    err: &anyhow::Error,
+) -> i32 {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let code = ErrorCode::of(err) as i32;
+        let message = format!("{:#}", err);
+        set_error_msg(error_buffer, error_buffer_size, &message);
+        set_last_error(code, &message, err.chain().map(|cause| cause.to_string()));
+        code
+    }))
+    .unwrap_or(PANIC)
+}
+
+pub fn report_panic<C: FfiChar>(
+    error_buffer: *mut C,
+    error_buffer_size: i32,
+    payload: Box<dyn Any + Send>,
 ) -> i32 {
+    _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let message = format!("Panic: {}", panic_message(payload.as_ref()));
+        set_error_msg(error_buffer, error_buffer_size, &message);
+        set_last_error(PANIC, &message, std::iter::once(message.clone()));
+    }));
+    // Dropping the payload can panic as well.
+    _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(payload)));
+    PANIC
+}
+
+pub fn set_error_msg<C: FfiChar>(
+    error_buffer: *mut C,
+    error_buffer_size: i32,
+    err: impl std::fmt::Display,
+) {
     if error_buffer.is_null() || error_buffer_size <= 0 {
-        return FAILURE;
+        return;
     }
 
     let msg = format!("{:#}", err);
-    let utf16_msg: Vec<u16> = msg.encode_utf16().collect();
-    let max_len = (error_buffer_size as usize).saturating_sub(1);
+    copy_to_buffer(error_buffer, error_buffer_size, &C::encode(&msg));
+}
+
+/// Copies as much of `src` as fits without splitting a code point and NUL-terminates it.
+/// Does nothing for a NULL or empty buffer.
+fn copy_to_buffer<C: FfiChar>(buffer: *mut C, buffer_size: i32, src: &[C]) {
+    if buffer.is_null() || buffer_size <= 0 {
+        return;
+    }
+
+    let max_len = (buffer_size as usize).saturating_sub(1);
 
     unsafe {
-        let copy_len = utf16_msg.len().min(max_len);
-        ptr::copy_nonoverlapping(utf16_msg.as_ptr(), error_buffer, copy_len);
-        *error_buffer.add(copy_len) = 0;
+        let copy_len = C::floor_boundary(src, max_len);
+        ptr::copy_nonoverlapping(src.as_ptr(), buffer, copy_len);
+        *buffer.add(copy_len) = C::NUL;
     }
+}
+
+struct LastError {
+    code: i32,
+    message: String,
+    /// The message of every error in the `anyhow` context chain, outermost first.
+    entries: Vec<String>,
+}
 
-    FAILURE
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
 }
 
-pub fn wchar_to_string(w_char_p: *const u16) -> Result<String> {
+pub fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+fn set_last_error(code: i32, message: &str, entries: impl Iterator<Item = String>) {
+    let last_error = LastError {
+        code,
+        message: message.to_string(),
+        entries: entries.collect(),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(last_error));
+}
+
+pub fn last_error_code() -> i32 {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(SUCCESS, |e| e.code))
+}
+
+pub fn last_error_entry_count() -> i32 {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(0, |e| e.entries.len() as i32))
+}
+
+/// Runs `f` on the full message (`index` is `None`) or one chain entry of the last error.
+/// Returns -1 when there is no last error or `index` is out of range.
+fn with_last_error_text<C: FfiChar>(index: Option<i32>, f: impl FnOnce(&[C]) -> i32) -> i32 {
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        let Some(last) = last.as_ref() else {
+            return -1;
+        };
+        let text = match index {
+            None => Some(&last.message),
+            Some(index) => usize::try_from(index)
+                .ok()
+                .and_then(|i| last.entries.get(i)),
+        };
+        text.map_or(-1, |text| f(&C::encode(text)))
+    })
+}
+
+/// Buffer size in code units of `C`, including the NUL terminator, needed to hold the text.
+/// Returns 0 when there is no such text.
+pub fn last_error_length<C: FfiChar>(index: Option<i32>) -> i32 {
+    with_last_error_text::<C>(index, |text| text.len() as i32 + 1).max(0)
+}
+
+/// Copies the text into `buffer`, truncating like `set_error_msg`, and returns its full length
+/// excluding the NUL terminator, so a result `>= buffer_size` means it was truncated.
+pub fn last_error_copy<C: FfiChar>(index: Option<i32>, buffer: *mut C, buffer_size: i32) -> i32 {
+    with_last_error_text(index, |text| {
+        copy_to_buffer(buffer, buffer_size, text);
+        text.len() as i32
+    })
+}
+
+/// Copies `text` like `last_error_copy` and returns its full length excluding the NUL terminator.
+pub fn copy_text<C: FfiChar>(text: &str, buffer: *mut C, buffer_size: i32) -> i32 {
+    let text = C::encode(text);
+    copy_to_buffer(buffer, buffer_size, &text);
+    text.len() as i32
+}
+
+pub fn ffi_to_string<C: FfiChar>(str_p: *const C) -> Result<String> {
     unsafe {
-        check_null_ptr!(w_char_p);
+        check_null_ptr!(str_p);
 
         let mut len = 0;
-        while *w_char_p.offset(len) != 0 {
+        while *str_p.offset(len) != C::NUL {
             len += 1;
         }
 
-        let slice = std::slice::from_raw_parts(w_char_p, len as usize);
+        let slice = std::slice::from_raw_parts(str_p, len as usize);
 
-        String::from_utf16(slice)
-            .with_context(|| format!("Invalid UTF-16 sequence in wchar_t* ({:?})", w_char_p))
+        C::decode(slice)
+            .with_context(|| format!("Invalid {} sequence in string ({:?})", C::ENCODING, str_p))
+            .code(ErrorCode::InvalidArgument)
     }
 }
 
-pub fn wchar_arr_to_vec(ptr: *const *const u16, len: i32) -> Result<Vec<Option<String>>> {
+pub fn ffi_arr_to_vec<C: FfiChar>(ptr: *const *const C, len: i32) -> Result<Vec<Option<String>>> {
     if len < 0 {
-        anyhow::bail!("Invalid length: {}", len);
+        bail_code!(ErrorCode::InvalidArgument, "Invalid length: {}", len);
     }
 
     if ptr.is_null() || len == 0 {
@@ -84,14 +287,16 @@ pub fn wchar_arr_to_vec(ptr: *const *const u16, len: i32) -> Result<Vec<Option<S
     unsafe {
         let paths = std::slice::from_raw_parts(
             ptr,
-            usize::try_from(len).context("Invalid length for wchar_t**")?,
+            usize::try_from(len)
+                .context("Invalid length for string array")
+                .code(ErrorCode::InvalidArgument)?,
         )
         .iter()
         .map(|&item| {
             if item.is_null() {
                 Ok(None)
             } else {
-                wchar_to_string(item).map(Some)
+                ffi_to_string(item).map(Some)
             }
         })
         .collect::<std::result::Result<Vec<Option<String>>, _>>()?;
@@ -99,3 +304,721 @@ pub fn wchar_arr_to_vec(ptr: *const *const u16, len: i32) -> Result<Vec<Option<S
         Ok(paths)
     }
 }
+
+/// Options for `mua_convert_stage_ex`. `struct_size` must be set to `sizeof(StageOptions)` as seen
+/// by the caller; new fields are only ever appended, and fields past `struct_size` read as zero.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct StageOptions<C = u16> {
+    pub struct_size: u32,
+    pub bg_in_path: *const C,
+    pub fx_in_paths: *const *const C,
+    pub fx_in_paths_count: c_int,
+    pub st_out_path: *const C,
+    pub nf_out_path: *const C,
+    /// Raw `DXGI_FORMAT` value for the background, or 0 for the default.
+    pub bg_format: u32,
+    pub progress_callback: ProgressCallback,
+    pub progress_user_data: *mut c_void,
+    /// Optional token from `mua_cancel_token_new`.
+    pub cancel_token: *const CancelToken,
+    /// `INPUT_*` flags; 0 applies EXIF orientation and embedded ICC profiles and dithers
+    /// high-bit-depth sources.
+    pub input_flags: u32,
+    /// One of the `TONE_MAPPING_*` operators, or 0 for the default.
+    pub tone_mapping: u32,
+    /// Exposure adjustment in stops for HDR sources.
+    pub exposure: f32,
+    /// One of the `FRAME_*` modes picking the frame of animated sources.
+    pub frame_selection: u32,
+    /// The frame index for `FRAME_INDEX`, or the time in milliseconds for `FRAME_TIME`.
+    pub frame_value: u32,
+    /// Optional; an animation whose frames fill the FX tiles instead of `fx_in_paths`.
+    pub fx_animation_path: *const C,
+}
+
+pub const STAGE_OPTIONS_V1_SIZE: usize = offset_of!(StageOptions, bg_format) + size_of::<u32>();
+
+pub fn read_sized_struct<T: Copy>(ptr: *const T, min_size: usize) -> Result<T> {
+    check_null_ptr!(ptr);
+
+    unsafe {
+        let struct_size = ptr::read_unaligned(ptr as *const u32) as usize;
+        if struct_size < min_size {
+            bail_code!(
+                ErrorCode::InvalidArgument,
+                "Invalid struct_size: {} (expected at least {})",
+                struct_size,
+                min_size
+            );
+        }
+
+        let mut value = MaybeUninit::<T>::zeroed();
+        ptr::copy_nonoverlapping(
+            ptr as *const u8,
+            value.as_mut_ptr() as *mut u8,
+            struct_size.min(size_of::<T>()),
+        );
+        Ok(value.assume_init())
+    }
+}
+
+/// Options for `mua_build_stage_package`, size-prefixed the same way as `StageOptions`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct StagePackageOptions<C = u16> {
+    pub struct_size: u32,
+    pub bg_in_path: *const C,
+    pub fx_in_paths: *const *const C,
+    pub fx_in_paths_count: c_int,
+    pub bg_format: u32,
+    pub out_folder: *const C,
+    pub stage_id: u32,
+    pub stage_name: *const C,
+    /// Must be 0 when `notes_field_line_name` is NULL.
+    pub notes_field_line_id: c_int,
+    /// Optional; the default notes field line is used when NULL.
+    pub notes_field_line_name: *const C,
+    pub progress_callback: ProgressCallback,
+    pub progress_user_data: *mut c_void,
+    /// Optional token from `mua_cancel_token_new`.
+    pub cancel_token: *const CancelToken,
+    /// `INPUT_*` flags; 0 applies EXIF orientation and embedded ICC profiles and dithers
+    /// high-bit-depth sources.
+    pub input_flags: u32,
+    /// One of the `TONE_MAPPING_*` operators, or 0 for the default.
+    pub tone_mapping: u32,
+    /// Exposure adjustment in stops for HDR sources.
+    pub exposure: f32,
+    /// One of the `FRAME_*` modes picking the frame of animated sources.
+    pub frame_selection: u32,
+    /// The frame index for `FRAME_INDEX`, or the time in milliseconds for `FRAME_TIME`.
+    pub frame_value: u32,
+    /// Optional; an animation whose frames fill the FX tiles instead of `fx_in_paths`.
+    pub fx_animation_path: *const C,
+}
+
+pub const STAGE_PACKAGE_OPTIONS_V1_SIZE: usize =
+    offset_of!(StagePackageOptions, notes_field_line_name) + size_of::<*const u16>();
+
+/// Options for `mua_convert_jk_ex`, size-prefixed the same way as `StageOptions`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct JacketOptions<C = u16> {
+    pub struct_size: u32,
+    pub in_path: *const C,
+    pub out_path: *const C,
+    pub progress_callback: ProgressCallback,
+    pub progress_user_data: *mut c_void,
+    /// Optional token from `mua_cancel_token_new`.
+    pub cancel_token: *const CancelToken,
+    /// `INPUT_*` flags; 0 applies EXIF orientation and embedded ICC profiles and dithers
+    /// high-bit-depth sources.
+    pub input_flags: u32,
+    /// One of the `TONE_MAPPING_*` operators, or 0 for the default.
+    pub tone_mapping: u32,
+    /// Exposure adjustment in stops for HDR sources.
+    pub exposure: f32,
+    /// One of the `FRAME_*` modes picking the frame of animated sources.
+    pub frame_selection: u32,
+    /// The frame index for `FRAME_INDEX`, or the time in milliseconds for `FRAME_TIME`.
+    pub frame_value: u32,
+}
+
+pub const JACKET_OPTIONS_V1_SIZE: usize =
+    offset_of!(JacketOptions, out_path) + size_of::<*const u16>();
+
+/// Converts the jacket described by `options` for `mua_convert_jk_ex`.
+pub fn convert_jk_from_raw<C: FfiChar>(options: &JacketOptions<C>) -> Result<()> {
+    check_null_ptr!(options.in_path);
+    check_null_ptr!(options.out_path);
+
+    let in_path_str = ffi_to_string(options.in_path)?;
+    let out_path_str = ffi_to_string(options.out_path)?;
+    let decode = decode_options_from_raw(
+        DecodeOptions::new(),
+        options.input_flags,
+        options.tone_mapping,
+        options.exposure,
+        options.frame_selection,
+        options.frame_value,
+    )?;
+
+    convert_jk_file(
+        Path::new(&in_path_str),
+        Path::new(&out_path_str),
+        &decode,
+        &progress_from_raw(
+            options.progress_callback,
+            options.progress_user_data,
+            options.cancel_token,
+        ),
+    )?;
+    Ok(())
+}
+
+/// Options for `mua_convert_atlas_ex`, size-prefixed the same way as `StageOptions`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AtlasOptions<C = u16> {
+    pub struct_size: u32,
+    pub in_path: *const C,
+    pub out_path: *const C,
+    pub frame_width: u32,
+    pub frame_height: u32,
+    /// Raw `DXGI_FORMAT` value, or 0 for BC3.
+    pub format: u32,
+    pub progress_callback: ProgressCallback,
+    pub progress_user_data: *mut c_void,
+    /// Optional token from `mua_cancel_token_new`.
+    pub cancel_token: *const CancelToken,
+    /// `INPUT_*` flags; 0 applies EXIF orientation and embedded ICC profiles and dithers
+    /// high-bit-depth sources.
+    pub input_flags: u32,
+    /// One of the `TONE_MAPPING_*` operators, or 0 for the default.
+    pub tone_mapping: u32,
+    /// Exposure adjustment in stops for HDR sources.
+    pub exposure: f32,
+}
+
+pub const ATLAS_OPTIONS_V1_SIZE: usize = offset_of!(AtlasOptions, format) + size_of::<u32>();
+
+/// Converts the atlas described by `options` for `mua_convert_atlas_ex`.
+pub fn convert_atlas_from_raw<C: FfiChar>(options: &AtlasOptions<C>) -> Result<()> {
+    check_null_ptr!(options.in_path);
+    check_null_ptr!(options.out_path);
+
+    let in_path_str = ffi_to_string(options.in_path)?;
+    let out_path_str = ffi_to_string(options.out_path)?;
+    let format =
+        dxgi_format_from_raw(options.format)?.unwrap_or(DXGI_FORMAT::DXGI_FORMAT_BC3_UNORM);
+    let decode = decode_options_from_raw(
+        DecodeOptions::new(),
+        options.input_flags,
+        options.tone_mapping,
+        options.exposure,
+        FRAME_INDEX,
+        0,
+    )?;
+
+    let dds = convert_atlas(
+        Path::new(&in_path_str),
+        options.frame_width,
+        options.frame_height,
+        format,
+        &decode,
+        &progress_from_raw(
+            options.progress_callback,
+            options.progress_user_data,
+            options.cancel_token,
+        ),
+    )?;
+    save_dds_file(dds, Path::new(&out_path_str))?;
+    Ok(())
+}
+
+/// Options for `mua_convert_preset_ex`, size-prefixed the same way as `StageOptions`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PresetOptions<C = u16> {
+    pub struct_size: u32,
+    pub preset_name: *const C,
+    pub in_path: *const C,
+    pub out_path: *const C,
+    pub progress_callback: ProgressCallback,
+    pub progress_user_data: *mut c_void,
+    /// Optional token from `mua_cancel_token_new`.
+    pub cancel_token: *const CancelToken,
+    /// `INPUT_*` flags, turning off decode steps on top of those the preset itself turns off.
+    pub input_flags: u32,
+    /// One of the `TONE_MAPPING_*` operators, or 0 for the preset's.
+    pub tone_mapping: u32,
+    /// Exposure adjustment in stops for HDR sources, added to the preset's.
+    pub exposure: f32,
+    /// One of the `FRAME_*` modes picking the frame of animated sources.
+    pub frame_selection: u32,
+    /// The frame index for `FRAME_INDEX`, or the time in milliseconds for `FRAME_TIME`.
+    pub frame_value: u32,
+}
+
+pub const PRESET_OPTIONS_V1_SIZE: usize =
+    offset_of!(PresetOptions, out_path) + size_of::<*const u16>();
+
+/// Options for `mua_build_jacket_package_ex`, size-prefixed the same way as `StageOptions`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct JacketPackageOptions<C = u16> {
+    pub struct_size: u32,
+    pub in_path: *const C,
+    pub music_id: u32,
+    pub out_folder: *const C,
+    /// Optional; the Music.xml whose jacket entry is pointed at the new file.
+    pub music_xml_path: *const C,
+    pub progress_callback: ProgressCallback,
+    pub progress_user_data: *mut c_void,
+    /// Optional token from `mua_cancel_token_new`.
+    pub cancel_token: *const CancelToken,
+    /// `INPUT_*` flags; 0 applies EXIF orientation and embedded ICC profiles and dithers
+    /// high-bit-depth sources.
+    pub input_flags: u32,
+    /// One of the `TONE_MAPPING_*` operators, or 0 for the default.
+    pub tone_mapping: u32,
+    /// Exposure adjustment in stops for HDR sources.
+    pub exposure: f32,
+    /// One of the `FRAME_*` modes picking the frame of animated sources.
+    pub frame_selection: u32,
+    /// The frame index for `FRAME_INDEX`, or the time in milliseconds for `FRAME_TIME`.
+    pub frame_value: u32,
+}
+
+pub const JACKET_PACKAGE_OPTIONS_V1_SIZE: usize =
+    offset_of!(JacketPackageOptions, music_xml_path) + size_of::<*const u16>();
+
+pub const BATCH_JOB_JACKET: c_int = 0;
+pub const BATCH_JOB_STAGE: c_int = 1;
+pub const BATCH_JOB_PRESET: c_int = 2;
+
+/// One entry of the `jobs` array passed to `mua_run_batch`. Every entry is size-prefixed like
+/// `StageOptions`, and the array stride is taken from the first entry's `struct_size`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BatchJob<C = u16> {
+    pub struct_size: u32,
+    /// One of the `BATCH_JOB_*` kinds.
+    pub kind: c_int,
+    /// Jacket/preset input, or the stage background.
+    pub in_path: *const C,
+    /// Jacket/preset output, or the stage st file.
+    pub out_path: *const C,
+    pub fx_in_paths: *const *const C,
+    pub fx_in_paths_count: c_int,
+    pub bg_format: u32,
+    pub nf_out_path: *const C,
+    pub preset_name: *const C,
+    /// `INPUT_*` flags. For preset jobs they turn off decode steps on top of the preset's own.
+    pub input_flags: u32,
+    /// One of the `TONE_MAPPING_*` operators, or 0 for the default (the preset's for preset
+    /// jobs).
+    pub tone_mapping: u32,
+    /// Exposure adjustment in stops for HDR sources, added to the preset's for preset jobs.
+    pub exposure: f32,
+    /// One of the `FRAME_*` modes picking the frame of animated sources.
+    pub frame_selection: u32,
+    /// The frame index for `FRAME_INDEX`, or the time in milliseconds for `FRAME_TIME`.
+    pub frame_value: u32,
+}
+
+pub const BATCH_JOB_V1_SIZE: usize = offset_of!(BatchJob, preset_name) + size_of::<*const u16>();
+
+/// Options for `mua_run_batch_ex`, size-prefixed the same way as `StageOptions`. The other fields
+/// are the parameters of `mua_run_batch`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BatchOptions<C = u16> {
+    pub struct_size: u32,
+    pub jobs: *const BatchJob<C>,
+    pub job_count: c_int,
+    pub thread_count: c_int,
+    pub job_results: *mut c_int,
+    pub job_error_buffers: *const *mut C,
+    pub job_error_buffer_size: c_int,
+    /// Optional token from `mua_cancel_token_new`; cancelling it stops the running jobs and
+    /// fails the remaining ones with `ErrorCode::Cancelled`.
+    pub cancel_token: *const CancelToken,
+}
+
+pub const BATCH_OPTIONS_V1_SIZE: usize =
+    offset_of!(BatchOptions, job_error_buffer_size) + size_of::<c_int>();
+
+/// Runs the jobs of `options` and fills in their results and error messages. Fails when any
+/// job failed.
+pub fn run_batch_from_raw<C: FfiChar>(options: &BatchOptions<C>) -> Result<()> {
+    check_null_ptr!(options.job_results);
+    if options.thread_count < 0 {
+        bail_code!(
+            ErrorCode::InvalidArgument,
+            "Invalid thread count: {}",
+            options.thread_count
+        );
+    }
+
+    let parsed_jobs = batch_jobs_from_raw(options.jobs, options.job_count)?;
+    let job_count = parsed_jobs.len();
+
+    let mut outcomes = Vec::with_capacity(job_count);
+    let mut runnable = Vec::new();
+    let mut runnable_slots = Vec::new();
+    for (slot, job) in parsed_jobs.into_iter().enumerate() {
+        match job {
+            Ok(job) => {
+                runnable.push(job);
+                runnable_slots.push(slot);
+                outcomes.push(Ok(()));
+            }
+            Err(err) => outcomes.push(Err(err)),
+        }
+    }
+
+    let cancel = unsafe { options.cancel_token.as_ref() }
+        .cloned()
+        .unwrap_or_default();
+    let results = run_batch_with_cancel(&runnable, options.thread_count as usize, &cancel)?;
+    for (slot, result) in runnable_slots.into_iter().zip(results) {
+        outcomes[slot] = result.map_err(anyhow::Error::from);
+    }
+
+    let job_results = unsafe { std::slice::from_raw_parts_mut(options.job_results, job_count) };
+    let job_error_buffers = if options.job_error_buffers.is_null() {
+        None
+    } else {
+        Some(unsafe { std::slice::from_raw_parts(options.job_error_buffers, job_count) })
+    };
+
+    let mut failed = 0;
+    for (slot, outcome) in outcomes.iter().enumerate() {
+        job_results[slot] = match outcome {
+            Ok(_) => SUCCESS,
+            Err(err) => {
+                failed += 1;
+                let buffer = job_error_buffers.map_or(ptr::null_mut(), |b| b[slot]);
+                report_error(buffer, options.job_error_buffer_size, err)
+            }
+        };
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} batch jobs failed", failed, job_count);
+    }
+    Ok(())
+}
+
+pub fn batch_jobs_from_raw<C: FfiChar>(
+    jobs: *const BatchJob<C>,
+    job_count: c_int,
+) -> Result<Vec<Result<Job>>> {
+    if job_count < 0 {
+        bail_code!(ErrorCode::InvalidArgument, "Invalid length: {}", job_count);
+    }
+    if job_count == 0 {
+        return Ok(Vec::new());
+    }
+    check_null_ptr!(jobs);
+
+    let stride = read_sized_struct(jobs, BATCH_JOB_V1_SIZE)?.struct_size as usize;
+    let base = jobs as *const u8;
+    Ok((0..job_count as usize)
+        .map(|i| {
+            let raw =
+                read_sized_struct(base.wrapping_add(i * stride) as *const BatchJob<C>, stride)?;
+            batch_job_from_raw(&raw)
+        })
+        .collect())
+}
+
+fn batch_job_from_raw<C: FfiChar>(raw: &BatchJob<C>) -> Result<Job> {
+    check_null_ptr!(raw.in_path);
+    check_null_ptr!(raw.out_path);
+    let decode = |base| {
+        decode_options_from_raw(
+            base,
+            raw.input_flags,
+            raw.tone_mapping,
+            raw.exposure,
+            raw.frame_selection,
+            raw.frame_value,
+        )
+    };
+
+    let job = match raw.kind {
+        BATCH_JOB_JACKET => Job::Jacket {
+            in_path: ffi_to_string(raw.in_path)?.into(),
+            out_path: ffi_to_string(raw.out_path)?.into(),
+            decode: decode(DecodeOptions::new())?,
+        },
+        BATCH_JOB_STAGE => {
+            check_null_ptr!(raw.nf_out_path);
+            Job::Stage {
+                stage: stage_builder_from_raw(
+                    raw.in_path,
+                    raw.fx_in_paths,
+                    raw.fx_in_paths_count,
+                    raw.bg_format,
+                )?
+                .decode(decode(DecodeOptions::new())?),
+                st_out_path: ffi_to_string(raw.out_path)?.into(),
+                nf_out_path: ffi_to_string(raw.nf_out_path)?.into(),
+            }
+        }
+        BATCH_JOB_PRESET => {
+            check_null_ptr!(raw.preset_name);
+            let mut preset = get_preset(&ffi_to_string(raw.preset_name)?)?;
+            preset.decode = decode(preset.decode)?;
+            Job::Preset {
+                preset,
+                in_path: ffi_to_string(raw.in_path)?.into(),
+                out_path: ffi_to_string(raw.out_path)?.into(),
+            }
+        }
+        kind => bail_code!(
+            ErrorCode::InvalidArgument,
+            "Unknown batch job kind: {}",
+            kind
+        ),
+    };
+    Ok(job)
+}
+
+pub fn stage_builder_from_raw<C: FfiChar>(
+    bg_in_path: *const C,
+    fx_in_paths: *const *const C,
+    fx_in_paths_count: c_int,
+    bg_format: u32,
+) -> Result<StageBuilder> {
+    check_null_ptr!(bg_in_path);
+    if fx_in_paths.is_null() && fx_in_paths_count > 0 {
+        bail_code!(
+            ErrorCode::InvalidArgument,
+            "NULL received for fx_in_paths while count is greater than 0"
+        );
+    }
+
+    let fx_path_vec = ffi_arr_to_vec(fx_in_paths, fx_in_paths_count)?;
+
+    let mut builder = StageBuilder::new().background(ffi_to_string(bg_in_path)?);
+    for (slot, path) in fx_path_vec.into_iter().enumerate() {
+        if let Some(path) = path {
+            builder = builder.fx(slot, path);
+        }
+    }
+    if let Some(format) = dxgi_format_from_raw(bg_format)? {
+        builder = builder.format(format);
+    }
+    Ok(builder)
+}
+
+/// Called with the step (`Step` as an integer), its NUL-terminated ASCII name, the overall
+/// fraction done (0.0-1.0) and the caller's user data. May be NULL.
+pub type ProgressCallback = Option<
+    unsafe extern "C" fn(
+        step: c_int,
+        step_name: *const c_char,
+        fraction: f32,
+        user_data: *mut c_void,
+    ),
+>;
+
+struct UserData(*mut c_void);
+
+// The caller is responsible for `user_data` being usable from the threads reporting progress.
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+pub fn progress_from_raw(
+    callback: ProgressCallback,
+    user_data: *mut c_void,
+    cancel_token: *const CancelToken,
+) -> Progress {
+    let progress = match callback {
+        Some(callback) => callback_progress(callback, UserData(user_data)),
+        None => Progress::none(),
+    };
+
+    match unsafe { cancel_token.as_ref() } {
+        Some(token) => progress.cancel_token(token.clone()),
+        None => progress,
+    }
+}
+
+fn callback_progress(
+    callback: unsafe extern "C" fn(c_int, *const c_char, f32, *mut c_void),
+    user_data: UserData,
+) -> Progress {
+    Progress::new(move |step, fraction| {
+        let step_name: &CStr = match step {
+            Step::Decoding => c"decoding",
+            Step::Resizing => c"resizing",
+            Step::Compressing => c"compressing",
+            Step::Writing => c"writing",
+        };
+        unsafe { callback(step as c_int, step_name.as_ptr(), fraction, user_data.get()) }
+    })
+}
+
+pub fn dxgi_format_from_raw(value: u32) -> Result<Option<DXGI_FORMAT>> {
+    let format = match value {
+        0 => return Ok(None),
+        28 => DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM,
+        71 => DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM,
+        74 => DXGI_FORMAT::DXGI_FORMAT_BC2_UNORM,
+        77 => DXGI_FORMAT::DXGI_FORMAT_BC3_UNORM,
+        98 => DXGI_FORMAT::DXGI_FORMAT_BC7_UNORM,
+        _ => bail_code!(
+            ErrorCode::InvalidArgument,
+            "Unsupported DXGI format: {}",
+            value
+        ),
+    };
+    Ok(Some(format))
+}
+
+/// Leave the pixels as stored instead of applying the EXIF orientation.
+pub const INPUT_IGNORE_ORIENTATION: u32 = 1 << 0;
+/// Treat the pixels as sRGB instead of converting them from an embedded ICC profile.
+pub const INPUT_IGNORE_COLOR_PROFILE: u32 = 1 << 1;
+/// Round instead of dithering when reducing 16-bit and HDR sources to 8 bits.
+pub const INPUT_NO_DITHER: u32 = 1 << 2;
+
+pub const TONE_MAPPING_CLAMP: u32 = 1;
+pub const TONE_MAPPING_REINHARD: u32 = 2;
+pub const TONE_MAPPING_ACES: u32 = 3;
+
+pub const FRAME_INDEX: u32 = 0;
+pub const FRAME_TIME: u32 = 1;
+pub const FRAME_REPRESENTATIVE: u32 = 2;
+
+/// Turns off the parts of `options` disabled by the `INPUT_*` `flags`, keeping the rest.
+pub fn apply_input_flags(options: DecodeOptions, flags: u32) -> Result<DecodeOptions> {
+    const KNOWN: u32 = INPUT_IGNORE_ORIENTATION | INPUT_IGNORE_COLOR_PROFILE | INPUT_NO_DITHER;
+    if flags & !KNOWN != 0 {
+        bail_code!(
+            ErrorCode::InvalidArgument,
+            "Unknown input flags: {:#x}",
+            flags
+        );
+    }
+    Ok(options
+        .orientation(options.orientation && flags & INPUT_IGNORE_ORIENTATION == 0)
+        .color_profile(options.color_profile && flags & INPUT_IGNORE_COLOR_PROFILE == 0)
+        .dither(options.dither && flags & INPUT_NO_DITHER == 0))
+}
+
+/// Applies the raw decode fields of an options struct to `base`: `flags` turn steps off, a
+/// non-zero `tone_mapping` replaces the operator, `exposure` is added to the base exposure and
+/// the frame selection replaces the base one.
+pub fn decode_options_from_raw(
+    base: DecodeOptions,
+    flags: u32,
+    tone_mapping: u32,
+    exposure: f32,
+    frame_selection: u32,
+    frame_value: u32,
+) -> Result<DecodeOptions> {
+    if !exposure.is_finite() {
+        bail_code!(ErrorCode::InvalidArgument, "Invalid exposure: {}", exposure);
+    }
+    let mut options = apply_input_flags(base, flags)?;
+    options = options.exposure(options.exposure + exposure);
+    options.tone_mapping = match tone_mapping {
+        0 => options.tone_mapping,
+        TONE_MAPPING_CLAMP => ToneMapping::Clamp,
+        TONE_MAPPING_REINHARD => ToneMapping::Reinhard,
+        TONE_MAPPING_ACES => ToneMapping::Aces,
+        _ => bail_code!(
+            ErrorCode::InvalidArgument,
+            "Unknown tone mapping operator: {}",
+            tone_mapping
+        ),
+    };
+    options.frame = match frame_selection {
+        FRAME_INDEX => FrameSelection::Index(frame_value as usize),
+        FRAME_TIME => FrameSelection::Time(Duration::from_millis(frame_value.into())),
+        FRAME_REPRESENTATIVE => FrameSelection::Representative,
+        _ => bail_code!(
+            ErrorCode::InvalidArgument,
+            "Unknown frame selection: {}",
+            frame_selection
+        ),
+    };
+    Ok(options)
+}
+
+/// Called with the level (1 = error ... 5 = trace), the NUL-terminated UTF-8 target and message,
+/// and the caller's user data. May be called from any thread running a conversion.
+pub type LogCallback = Option<
+    unsafe extern "C" fn(
+        level: c_int,
+        target: *const c_char,
+        message: *const c_char,
+        user_data: *mut c_void,
+    ),
+>;
+
+struct LogSink {
+    callback: unsafe extern "C" fn(c_int, *const c_char, *const c_char, *mut c_void),
+    user_data: UserData,
+}
+
+static LOG_SINK: RwLock<Option<LogSink>> = RwLock::new(None);
+
+struct FfiLogger;
+
+impl log::Log for FfiLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Copied out so the guard is released before calling back: a callback that calls
+        // `mua_set_log_callback` would otherwise deadlock on the write lock.
+        let (callback, user_data) = {
+            let sink = LOG_SINK.read().unwrap_or_else(|e| e.into_inner());
+            let Some(sink) = sink.as_ref() else {
+                return;
+            };
+            (sink.callback, sink.user_data.get())
+        };
+
+        let target = CString::new(record.target().replace('\0', "")).unwrap_or_default();
+        let message = CString::new(record.args().to_string().replace('\0', "")).unwrap_or_default();
+        unsafe {
+            callback(
+                record.level() as c_int,
+                target.as_ptr(),
+                message.as_ptr(),
+                user_data,
+            )
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static FFI_LOGGER: FfiLogger = FfiLogger;
+
+/// Routes `log` records up to `level` (0 = off ... 5 = trace) to `callback`, or stops
+/// forwarding when `callback` is NULL. Has no effect on records if a Rust consumer
+/// already installed its own logger.
+pub fn set_log_callback(callback: LogCallback, user_data: *mut c_void, level: c_int) {
+    const LEVELS: [log::LevelFilter; 6] = [
+        log::LevelFilter::Off,
+        log::LevelFilter::Error,
+        log::LevelFilter::Warn,
+        log::LevelFilter::Info,
+        log::LevelFilter::Debug,
+        log::LevelFilter::Trace,
+    ];
+
+    let sink = callback.map(|callback| LogSink {
+        callback,
+        user_data: UserData(user_data),
+    });
+    let filter = match sink {
+        Some(_) => LEVELS[level.clamp(0, 5) as usize],
+        None => log::LevelFilter::Off,
+    };
+    *LOG_SINK.write().unwrap_or_else(|e| e.into_inner()) = sink;
+
+    static INSTALLED: OnceLock<bool> = OnceLock::new();
+    if *INSTALLED.get_or_init(|| log::set_logger(&FFI_LOGGER).is_ok()) {
+        log::set_max_level(filter);
+    }
+}
@@ -0,0 +1,497 @@
+//! `mua`: runs the `img` operations from the command line. Every command prints one JSON
+//! object to stdout and exits with the `ErrorCode` of the failure, or 0 on success.
+
+use anyhow::{Context, Result};
+use mua_lib::bail_code;
+use mua_lib::error::{ErrorCode, WithCode};
+use mua_lib::img;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage:
+  mua validate <image>
+  mua jacket <image> <out.dds>
+  mua jacket <image> --music-id <id> --out-dir <dir> [--music-xml <Music.xml>]
+  mua stage <background> <st.afb> <nf.afb> [--fx <slot>=<image>]... [--format <format>]
+  mua stage <background> --stage-id <id> --name <name> --out-dir <dir> [--fx <slot>=<image>]...
+            [--format <format>]
+  mua preset <name> <image> <out.dds> [--presets <file>]
+  mua afb extract <file.afb> <out-dir>
+  mua afb inspect <file.afb>
+  mua afb repack <file.afb> <out.afb> <chunk>=<file>...
+  mua dds info <file.dds>
+  mua cache prune <dir> [--max-size <bytes>] [--max-age <days>]
+
+Chunks of every type are numbered together from 1, in file order, matching the file names
+written by `afb extract`. Besides DDS, extraction recognises embedded PNG, GNF, GTF and nested
+AFB files and writes a manifest.
+Formats: RGBA8, BC1, BC2, BC3, BC7.
+jacket, stage and preset accept --cache-dir <dir> to reuse earlier conversions.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if matches!(
+        args.first().map(String::as_str),
+        Some("help" | "--help" | "-h")
+    ) {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    match run(&args) {
+        Ok(fields) => {
+            let mut output = vec![("ok", Value::Bool(true))];
+            output.extend(fields);
+            println!("{}", Value::Object(output));
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            let code = ErrorCode::of(&err);
+            let output = Value::Object(vec![
+                ("ok", Value::Bool(false)),
+                ("code", Value::Number(code as u64)),
+                ("error", Value::String(format!("{:#}", err))),
+            ]);
+            println!("{}", output);
+            ExitCode::from(code as u8)
+        }
+    }
+}
+
+type Fields = Vec<(&'static str, Value)>;
+
+fn run(args: &[String]) -> Result<Fields> {
+    let Some((command, rest)) = args.split_first() else {
+        bail_code!(ErrorCode::InvalidArgument, "{}", USAGE);
+    };
+    let (command, rest) = match command.as_str() {
+        "afb" | "dds" | "cache" => match rest.split_first() {
+            Some((sub, rest)) => (format!("{} {}", command, sub), rest),
+            None => bail_code!(ErrorCode::InvalidArgument, "{}", USAGE),
+        },
+        _ => (command.clone(), rest),
+    };
+    let args = Args::parse(rest)?;
+    if let Some(cache_dir) = args.option("cache-dir")? {
+        img::set_cache_dir(Some(Path::new(cache_dir)))?;
+    }
+
+    match command.as_str() {
+        "validate" => validate(&args),
+        "jacket" => jacket(&args),
+        "stage" => stage(&args),
+        "preset" => preset(&args),
+        "afb extract" => afb_extract(&args),
+        "afb inspect" => afb_inspect(&args),
+        "afb repack" => afb_repack(&args),
+        "dds info" => dds_info(&args),
+        "cache prune" => cache_prune(&args),
+        _ => bail_code!(
+            ErrorCode::InvalidArgument,
+            "Unknown command `{}`\n\n{}",
+            command,
+            USAGE
+        ),
+    }
+}
+
+fn validate(args: &Args) -> Result<Fields> {
+    args.expect_positionals(1)?;
+    let path = args.path(0)?;
+    img::is_valid_image(&path)?;
+    Ok(vec![("path", path.as_path().into())])
+}
+
+fn jacket(args: &Args) -> Result<Fields> {
+    let in_path = args.path(0)?;
+    let output = match args.option("music-id")? {
+        Some(music_id) => {
+            args.expect_positionals(1)?;
+            let music_id: u32 = parse_number(music_id, "music id")?;
+            let out_dir = args.required_option("out-dir")?;
+            let music_xml = args.option("music-xml")?.map(Path::new);
+            img::build_jacket_package(&in_path, music_id, Path::new(out_dir), music_xml)?
+        }
+        None => {
+            args.expect_positionals(2)?;
+            let out_path = args.path(1)?;
+            let decode = img::DecodeOptions::default();
+            img::convert_jk_file(&in_path, &out_path, &decode, &img::Progress::none())?;
+            out_path
+        }
+    };
+    Ok(vec![("output", output.as_path().into())])
+}
+
+fn stage(args: &Args) -> Result<Fields> {
+    let mut builder = img::StageBuilder::new().background(args.path(0)?);
+    for fx in args.options("fx") {
+        let (slot, path) = fx
+            .split_once('=')
+            .with_context(|| format!("Expected `<slot>=<image>` for --fx, got `{}`", fx))
+            .code(ErrorCode::InvalidArgument)?;
+        builder = builder.fx(parse_number(slot, "FX slot")?, path);
+    }
+    if let Some(format) = args.option("format")? {
+        builder = builder.format(img::parse_format(format).code(ErrorCode::InvalidArgument)?);
+    }
+
+    match args.option("out-dir")? {
+        Some(out_dir) => {
+            args.expect_positionals(1)?;
+            let stage_id: u32 = parse_number(args.required_option("stage-id")?, "stage id")?;
+            let info = img::StageInfo::new(stage_id, args.required_option("name")?);
+            let stage_dir = img::build_stage_package(&builder, &info, Path::new(out_dir))?;
+            Ok(vec![("output", stage_dir.as_path().into())])
+        }
+        None => {
+            args.expect_positionals(3)?;
+            let (st_out_path, nf_out_path) = (args.path(1)?, args.path(2)?);
+            builder.build_to(&st_out_path, &nf_out_path)?;
+            Ok(vec![
+                ("st", st_out_path.as_path().into()),
+                ("nf", nf_out_path.as_path().into()),
+            ])
+        }
+    }
+}
+
+fn preset(args: &Args) -> Result<Fields> {
+    args.expect_positionals(3)?;
+    if let Some(presets) = args.option("presets")? {
+        img::load_presets(Path::new(presets))?;
+    }
+    let out_path = args.path(2)?;
+    img::convert_preset(args.positional(0)?, &args.path(1)?, &out_path)?;
+    Ok(vec![("output", out_path.as_path().into())])
+}
+
+fn afb_extract(args: &Args) -> Result<Fields> {
+    args.expect_positionals(2)?;
+    let in_path = args.path(0)?;
+    let out_dir = args.positional(1)?;
+    let chunks = img::extract_afb(&in_path, out_dir)?;
+    Ok(vec![
+        ("output", Path::new(out_dir).into()),
+        ("chunks", Value::Number(chunks as u64)),
+    ])
+}
+
+fn afb_inspect(args: &Args) -> Result<Fields> {
+    args.expect_positionals(1)?;
+    let chunks = img::inspect_afb(&args.path(0)?)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, payload)| {
+            Value::Object(vec![
+                ("chunk", Value::Number(i as u64 + 1)),
+                ("type", Value::String(payload.signature.name.to_string())),
+                ("offset", Value::Number(payload.start as u64)),
+                ("size", Value::Number((payload.end - payload.start) as u64)),
+            ])
+        })
+        .collect();
+    Ok(vec![("chunks", Value::Array(chunks))])
+}
+
+fn afb_repack(args: &Args) -> Result<Fields> {
+    if args.positionals.len() < 3 {
+        bail_code!(ErrorCode::InvalidArgument, "{}", USAGE);
+    }
+    let (in_path, out_path) = (args.path(0)?, args.path(1)?);
+    let replacements = args.positionals[2..]
+        .iter()
+        .map(|arg| -> Result<(usize, &Path)> {
+            let (chunk, path) = arg
+                .split_once('=')
+                .with_context(|| format!("Expected `<chunk>=<file>`, got `{}`", arg))
+                .code(ErrorCode::InvalidArgument)?;
+            let chunk: usize = parse_number(chunk, "chunk")?;
+            if chunk == 0 {
+                bail_code!(ErrorCode::InvalidArgument, "Chunks are numbered from 1");
+            }
+            Ok((chunk - 1, Path::new(path)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    img::repack_afb(&in_path, &out_path, &replacements)?;
+    Ok(vec![
+        ("output", out_path.as_path().into()),
+        ("replaced", Value::Number(replacements.len() as u64)),
+    ])
+}
+
+fn dds_info(args: &Args) -> Result<Fields> {
+    args.expect_positionals(1)?;
+    let info = img::read_dds_info(&args.path(0)?)?;
+    let optional = |value: Option<Value>| value.unwrap_or(Value::Null);
+    Ok(vec![
+        ("width", Value::Number(info.width.into())),
+        ("height", Value::Number(info.height.into())),
+        ("depth", Value::Number(info.depth.into())),
+        ("mip_count", Value::Number(info.mip_count.into())),
+        ("array_size", Value::Number(info.array_size.into())),
+        ("four_cc", optional(info.four_cc.map(Value::String))),
+        (
+            "dxgi_format",
+            optional(info.dxgi_format.map(|f| Value::Number(f.into()))),
+        ),
+    ])
+}
+
+fn cache_prune(args: &Args) -> Result<Fields> {
+    args.expect_positionals(1)?;
+    let max_size = match args.option("max-size")? {
+        Some(max_size) => parse_number(max_size, "max size")?,
+        None => u64::MAX,
+    };
+    let max_age = match args.option("max-age")? {
+        Some(days) => {
+            let secs = parse_number::<u64>(days, "max age")?
+                .checked_mul(24 * 60 * 60)
+                .with_context(|| format!("Invalid max age: `{}`", days))
+                .code(ErrorCode::InvalidArgument)?;
+            Some(Duration::from_secs(secs))
+        }
+        None => None,
+    };
+    let stats = img::ConversionCache::new(args.path(0)?)?.prune(max_size, max_age)?;
+    Ok(vec![
+        ("removed", Value::Number(stats.removed_entries as u64)),
+        ("removed_bytes", Value::Number(stats.removed_bytes)),
+        ("kept", Value::Number(stats.kept_entries as u64)),
+        ("kept_bytes", Value::Number(stats.kept_bytes)),
+    ])
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, name: &str) -> Result<T> {
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("Invalid {}: `{}`", name, value))
+        .code(ErrorCode::InvalidArgument)
+}
+
+/// Positional arguments and `--name value` options, in the order given.
+struct Args {
+    positionals: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut parsed = Args {
+            positionals: Vec::new(),
+            options: Vec::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .with_context(|| format!("Missing value for --{}", name))
+                        .code(ErrorCode::InvalidArgument)?;
+                    parsed.options.push((name.to_string(), value.clone()));
+                }
+                None => parsed.positionals.push(arg.clone()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn expect_positionals(&self, count: usize) -> Result<()> {
+        if self.positionals.len() != count {
+            bail_code!(ErrorCode::InvalidArgument, "{}", USAGE);
+        }
+        Ok(())
+    }
+
+    fn positional(&self, index: usize) -> Result<&str> {
+        match self.positionals.get(index) {
+            Some(value) => Ok(value.as_str()),
+            None => bail_code!(ErrorCode::InvalidArgument, "{}", USAGE),
+        }
+    }
+
+    fn path(&self, index: usize) -> Result<PathBuf> {
+        self.positional(index).map(PathBuf::from)
+    }
+
+    fn options(&self, name: &str) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn option(&self, name: &str) -> Result<Option<&str>> {
+        let values = self.options(name);
+        let value = values.first().copied();
+        if values.len() > 1 {
+            bail_code!(
+                ErrorCode::InvalidArgument,
+                "--{} given more than once",
+                name
+            );
+        }
+        Ok(value)
+    }
+
+    fn required_option(&self, name: &str) -> Result<&str> {
+        self.option(name)?
+            .with_context(|| format!("Missing --{}", name))
+            .code(ErrorCode::InvalidArgument)
+    }
+}
+
+enum Value {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(&'static str, Value)>),
+}
+
+impl From<&Path> for Value {
+    fn from(path: &Path) -> Self {
+        Value::String(path.display().to_string())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::String(value) => write_json_string(f, value),
+            Value::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_json_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_args_parse() -> Result<()> {
+        let args = Args::parse(&strings(&[
+            "bg.png", "--fx", "0=a.png", "st.afb", "--format", "BC3", "--fx", "2=b.png",
+        ]))?;
+        assert_eq!(args.positionals, ["bg.png", "st.afb"]);
+        assert_eq!(args.options("fx"), ["0=a.png", "2=b.png"]);
+        assert_eq!(args.option("format")?, Some("BC3"));
+        assert_eq!(args.option("out-dir")?, None);
+        assert_eq!(args.path(1)?, PathBuf::from("st.afb"));
+
+        assert!(args.option("fx").is_err(), "--fx is given twice");
+        assert!(args.required_option("name").is_err());
+        assert!(args.positional(2).is_err());
+        assert!(args.expect_positionals(3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_errors() {
+        let err = Args::parse(&strings(&["in.png", "--music-id"]))
+            .err()
+            .unwrap();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::InvalidArgument);
+        assert_eq!(format!("{:#}", err), "Missing value for --music-id");
+
+        for args in [
+            &[][..],
+            &["afb"],
+            &["convert", "in.png"],
+            &["jacket", "in.png"],
+            &["jacket", "in.png", "--music-id", "12a", "--out-dir", "out"],
+            &["stage", "bg.png", "st.afb", "nf.afb", "--fx", "a.png"],
+            &["stage", "bg.png", "st.afb", "nf.afb", "--format", "DXT9"],
+            &[
+                "stage",
+                "bg.png",
+                "st.afb",
+                "nf.afb",
+                "--fx",
+                "18446744073709551615=x.png",
+            ],
+            &["afb", "repack", "in.afb", "out.afb", "0=chunk.dds"],
+            &["cache", "prune", "cache", "--max-age", "213503982334602"],
+        ] {
+            let err = run(&strings(args)).err().unwrap();
+            assert_eq!(
+                ErrorCode::of(&err),
+                ErrorCode::InvalidArgument,
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_output() {
+        let output = Value::Object(vec![
+            ("ok", Value::Bool(false)),
+            ("code", Value::Number(5)),
+            ("path", Path::new("C:\\stages\\\"new\"").into()),
+            (
+                "error",
+                Value::String("line 1\nline 2\t\r\u{1}é".to_string()),
+            ),
+            (
+                "chunks",
+                Value::Array(vec![Value::Null, Value::Array(Vec::new())]),
+            ),
+        ]);
+        assert_eq!(
+            output.to_string(),
+            r#"{"ok":false,"code":5,"path":"C:\\stages\\\"new\"","error":"line 1\nline 2\t\r\u0001é","chunks":[null,[]]}"#
+        );
+    }
+}
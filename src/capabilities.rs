@@ -0,0 +1,97 @@
+use image::ImageFormat;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const CAP_INPUT_PNG: u64 = 1 << 0;
+pub const CAP_INPUT_JPEG: u64 = 1 << 1;
+pub const CAP_INPUT_BMP: u64 = 1 << 2;
+pub const CAP_INPUT_GIF: u64 = 1 << 3;
+pub const CAP_INPUT_WEBP: u64 = 1 << 4;
+pub const CAP_INPUT_TGA: u64 = 1 << 5;
+pub const CAP_INPUT_TIFF: u64 = 1 << 6;
+pub const CAP_INPUT_ICO: u64 = 1 << 7;
+pub const CAP_INPUT_HDR: u64 = 1 << 8;
+pub const CAP_INPUT_OPENEXR: u64 = 1 << 9;
+pub const CAP_INPUT_DDS: u64 = 1 << 10;
+pub const CAP_INPUT_QOI: u64 = 1 << 11;
+/// Flattened composites of PSD and PSB documents, decoded by this crate.
+pub const CAP_INPUT_PSD: u64 = 1 << 12;
+
+pub const CAP_DXGI_R8G8B8A8_UNORM: u64 = 1 << 16;
+pub const CAP_DXGI_BC1_UNORM: u64 = 1 << 17;
+pub const CAP_DXGI_BC2_UNORM: u64 = 1 << 18;
+pub const CAP_DXGI_BC3_UNORM: u64 = 1 << 19;
+pub const CAP_DXGI_BC7_UNORM: u64 = 1 << 20;
+
+pub const CAP_AFB_EXTRACT: u64 = 1 << 24;
+pub const CAP_AFB_STAGE: u64 = 1 << 25;
+pub const CAP_AFB_STAGE_PACKAGE: u64 = 1 << 26;
+/// `afb:extract` also carves PNG, GNF, GTF and nested AFB payloads and writes a manifest.
+pub const CAP_AFB_PAYLOADS: u64 = 1 << 27;
+
+/// Reserved for the audio and video pipelines; never set by this build.
+pub const CAP_AUDIO: u64 = 1 << 32;
+pub const CAP_VIDEO: u64 = 1 << 33;
+
+/// Decoding applies EXIF orientation and embedded ICC profiles unless told not to.
+pub const CAP_DECODE_EXIF_ICC: u64 = 1 << 40;
+/// HDR and OpenEXR sources are tone mapped with a selectable operator and exposure.
+pub const CAP_DECODE_TONE_MAPPING: u64 = 1 << 41;
+/// A single frame of animated GIF, APNG and WebP sources can be picked, and `convert_atlas`
+/// lays out every frame.
+pub const CAP_DECODE_ANIMATION: u64 = 1 << 42;
+
+/// Jacket, preset and stage conversions can be cached with `set_cache_dir`.
+pub const CAP_CACHE_CONVERSION: u64 = 1 << 48;
+
+const INPUT_FORMATS: [(u64, ImageFormat, &str); 12] = [
+    (CAP_INPUT_PNG, ImageFormat::Png, "input:png"),
+    (CAP_INPUT_JPEG, ImageFormat::Jpeg, "input:jpeg"),
+    (CAP_INPUT_BMP, ImageFormat::Bmp, "input:bmp"),
+    (CAP_INPUT_GIF, ImageFormat::Gif, "input:gif"),
+    (CAP_INPUT_WEBP, ImageFormat::WebP, "input:webp"),
+    (CAP_INPUT_TGA, ImageFormat::Tga, "input:tga"),
+    (CAP_INPUT_TIFF, ImageFormat::Tiff, "input:tiff"),
+    (CAP_INPUT_ICO, ImageFormat::Ico, "input:ico"),
+    (CAP_INPUT_HDR, ImageFormat::Hdr, "input:hdr"),
+    (CAP_INPUT_OPENEXR, ImageFormat::OpenExr, "input:openexr"),
+    (CAP_INPUT_DDS, ImageFormat::Dds, "input:dds"),
+    (CAP_INPUT_QOI, ImageFormat::Qoi, "input:qoi"),
+];
+
+const FIXED: [(u64, &str); 14] = [
+    (CAP_INPUT_PSD, "input:psd"),
+    (CAP_DXGI_R8G8B8A8_UNORM, "dxgi:r8g8b8a8_unorm"),
+    (CAP_DXGI_BC1_UNORM, "dxgi:bc1_unorm"),
+    (CAP_DXGI_BC2_UNORM, "dxgi:bc2_unorm"),
+    (CAP_DXGI_BC3_UNORM, "dxgi:bc3_unorm"),
+    (CAP_DXGI_BC7_UNORM, "dxgi:bc7_unorm"),
+    (CAP_AFB_EXTRACT, "afb:extract"),
+    (CAP_AFB_STAGE, "afb:stage"),
+    (CAP_AFB_STAGE_PACKAGE, "afb:stage_package"),
+    (CAP_AFB_PAYLOADS, "afb:payloads"),
+    (CAP_DECODE_EXIF_ICC, "decode:exif_icc"),
+    (CAP_DECODE_TONE_MAPPING, "decode:tone_mapping"),
+    (CAP_DECODE_ANIMATION, "decode:animation"),
+    (CAP_CACHE_CONVERSION, "cache:conversion"),
+];
+
+/// `CAP_*` bits supported by this build. Input formats follow the decoders compiled into
+/// the `image` crate.
+pub fn capabilities() -> u64 {
+    let inputs = INPUT_FORMATS
+        .iter()
+        .filter(|(_, format, _)| format.reading_enabled())
+        .fold(0, |bits, (bit, _, _)| bits | bit);
+    FIXED.iter().fold(inputs, |bits, (bit, _)| bits | bit)
+}
+
+/// Names of the supported capabilities, e.g. `input:png` or `dxgi:bc1_unorm`.
+pub fn capability_names() -> Vec<&'static str> {
+    let inputs = INPUT_FORMATS
+        .iter()
+        .filter(|(_, format, _)| format.reading_enabled())
+        .map(|(_, _, name)| *name);
+    let fixed = FIXED.iter().map(|(_, name)| *name);
+    inputs.chain(fixed).collect()
+}
@@ -0,0 +1,117 @@
+use std::fmt;
+
+/// Stable status codes returned by every exported function.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Success = 0,
+    /// Any error that does not fit one of the categories below.
+    Failure = 1,
+    Panic = 2,
+    Io = 3,
+    Decode = 4,
+    InvalidArgument = 5,
+    Compression = 6,
+    ContainerFormat = 7,
+    Cancelled = 8,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::Success => "success",
+            ErrorCode::Failure => "failure",
+            ErrorCode::Panic => "panic",
+            ErrorCode::Io => "I/O error",
+            ErrorCode::Decode => "decode error",
+            ErrorCode::InvalidArgument => "invalid argument",
+            ErrorCode::Compression => "compression error",
+            ErrorCode::ContainerFormat => "container format error",
+            ErrorCode::Cancelled => "cancelled",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ErrorCode {
+    /// Returns the code attached to `err` with `WithCode::code` or `bail_code!`, falling back to
+    /// the `img::Error`, I/O and image errors found in its chain.
+    pub fn of(err: &anyhow::Error) -> ErrorCode {
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<CodedError>() {
+                return err.code;
+            }
+            if let Some(err) = cause.downcast_ref::<crate::img::Error>() {
+                return err.code();
+            }
+            if cause.is::<std::io::Error>() {
+                return ErrorCode::Io;
+            }
+            if let Some(err) = cause.downcast_ref::<image::ImageError>() {
+                return match err {
+                    image::ImageError::IoError(_) => ErrorCode::Io,
+                    image::ImageError::Decoding(_) | image::ImageError::Unsupported(_) => {
+                        ErrorCode::Decode
+                    }
+                    image::ImageError::Parameter(_) | image::ImageError::Limits(_) => {
+                        ErrorCode::InvalidArgument
+                    }
+                    image::ImageError::Encoding(_) => ErrorCode::Failure,
+                };
+            }
+        }
+        ErrorCode::Failure
+    }
+
+    /// Tags `err` with this code. Unlike a context, the tag adds no message of its own: the
+    /// error displays, and its chain reads, exactly as before.
+    pub fn attach(self, err: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(CodedError {
+            code: self,
+            error: err,
+        })
+    }
+}
+
+/// An error tagged with the `ErrorCode` to report for it. Displays as the wrapped error and
+/// continues the chain with the wrapped error's source.
+struct CodedError {
+    code: ErrorCode,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.error, f)
+    }
+}
+
+impl fmt::Debug for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for CodedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait WithCode<T> {
+    /// Tags the error with `code`, which `ErrorCode::of` reports instead of guessing.
+    fn code(self, code: ErrorCode) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithCode<T> for Result<T, E> {
+    fn code(self, code: ErrorCode) -> anyhow::Result<T> {
+        self.map_err(|err| code.attach(err.into()))
+    }
+}
+
+#[macro_export]
+macro_rules! bail_code {
+    ($code:expr, $($arg:tt)*) => {
+        return Err($crate::error::ErrorCode::attach($code, anyhow::anyhow!($($arg)*)))
+    };
+}
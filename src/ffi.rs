@@ -0,0 +1,499 @@
+//! C ABI exports of `mua_lib.dll`, compiled with the `ffi` feature.
+
+use crate::api::{
+    ATLAS_OPTIONS_V1_SIZE, AtlasOptions, BATCH_OPTIONS_V1_SIZE, BatchJob, BatchOptions,
+    JACKET_OPTIONS_V1_SIZE, JACKET_PACKAGE_OPTIONS_V1_SIZE, JacketOptions, JacketPackageOptions,
+    LogCallback, PRESET_OPTIONS_V1_SIZE, PresetOptions, STAGE_OPTIONS_V1_SIZE,
+    STAGE_PACKAGE_OPTIONS_V1_SIZE, SUCCESS, StageOptions, StagePackageOptions, clear_last_error,
+    convert_atlas_from_raw, convert_jk_from_raw, copy_text, decode_options_from_raw,
+    ffi_arr_to_vec, ffi_to_string, last_error_code, last_error_copy, last_error_entry_count,
+    last_error_length, progress_from_raw, read_sized_struct, report_error, report_panic,
+    run_batch_from_raw, set_log_callback, stage_builder_from_raw,
+};
+use crate::error::ErrorCode;
+use crate::img::CancelToken;
+use crate::{api, bail_code, capabilities, check_null_ptr, img};
+use std::ffi::{c_char, c_int, c_void};
+use std::path::Path;
+use std::time::Duration;
+
+/// Status code of the last failed call on this thread, or `SUCCESS`.
+/// Every exported conversion function clears the last error when it starts.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_code() -> c_int {
+    last_error_code()
+}
+
+/// Buffer size, including the NUL terminator, needed by `mua_last_error_copy`.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_length() -> c_int {
+    last_error_length::<u16>(None)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_length_utf8() -> c_int {
+    last_error_length::<c_char>(None)
+}
+
+/// Copies the full last error message and returns its length without the NUL terminator,
+/// or -1 when there is no last error.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_copy(buffer: *mut u16, buffer_size: c_int) -> c_int {
+    last_error_copy(None, buffer, buffer_size)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_copy_utf8(buffer: *mut c_char, buffer_size: c_int) -> c_int {
+    last_error_copy(None, buffer, buffer_size)
+}
+
+/// Number of entries in the last error's context chain, outermost first.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_entry_count() -> c_int {
+    last_error_entry_count()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_entry_length(index: c_int) -> c_int {
+    last_error_length::<u16>(Some(index))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_entry_length_utf8(index: c_int) -> c_int {
+    last_error_length::<c_char>(Some(index))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_entry_copy(
+    index: c_int,
+    buffer: *mut u16,
+    buffer_size: c_int,
+) -> c_int {
+    last_error_copy(Some(index), buffer, buffer_size)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_last_error_entry_copy_utf8(
+    index: c_int,
+    buffer: *mut c_char,
+    buffer_size: c_int,
+) -> c_int {
+    last_error_copy(Some(index), buffer, buffer_size)
+}
+
+/// Registers a callback receiving log events (detected input formats, resize dimensions,
+/// DXGI formats, chunk offsets, bytes written) up to `level`: 0 = off, 1 = error, 2 = warn,
+/// 3 = info, 4 = debug, 5 = trace. Pass NULL to unregister.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_set_log_callback(
+    callback: LogCallback,
+    user_data: *mut c_void,
+    level: c_int,
+) {
+    set_log_callback(callback, user_data, level);
+}
+
+/// Creates a cancellation token to pass to conversions. Free it with `mua_cancel_token_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_cancel_token_new() -> *mut CancelToken {
+    Box::into_raw(Box::new(CancelToken::new()))
+}
+
+/// Requests cancellation; conversions using the token stop at their next step and return
+/// `ErrorCode::Cancelled`. Safe to call from any thread while a conversion is running.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_cancel_token_cancel(token: *const CancelToken) {
+    if let Some(token) = unsafe { token.as_ref() } {
+        token.cancel();
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_cancel_token_free(token: *mut CancelToken) {
+    if !token.is_null() {
+        drop(unsafe { Box::from_raw(token) });
+    }
+}
+
+/// Crate version as a static NUL-terminated UTF-8 string, e.g. "1.0.0".
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
+
+/// Crate version packed as `major << 16 | minor << 8 | patch`.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_version_number() -> u32 {
+    const fn parse(s: &str) -> u32 {
+        let bytes = s.as_bytes();
+        let mut value = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            value = value * 10 + (bytes[i] - b'0') as u32;
+            i += 1;
+        }
+        value
+    }
+    const VERSION: u32 = parse(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+        | parse(env!("CARGO_PKG_VERSION_MINOR")) << 8
+        | parse(env!("CARGO_PKG_VERSION_PATCH"));
+    VERSION
+}
+
+/// Bitmask of the `capabilities::CAP_*` features supported by this build.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_capabilities() -> u64 {
+    capabilities::capabilities()
+}
+
+/// Copies the supported capability names as a comma-separated list (e.g. "input:png,afb:stage")
+/// and returns its full length excluding the NUL terminator.
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_capability_list_copy(buffer: *mut u16, buffer_size: c_int) -> c_int {
+    copy_text(
+        &capabilities::capability_names().join(","),
+        buffer,
+        buffer_size,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mua_capability_list_copy_utf8(buffer: *mut c_char, buffer_size: c_int) -> c_int {
+    copy_text(
+        &capabilities::capability_names().join(","),
+        buffer,
+        buffer_size,
+    )
+}
+
+api!(validate_image(in_path: *const Char) {
+    check_null_ptr!(in_path);
+    let path_str = ffi_to_string(in_path)?;
+    img::is_valid_image(Path::new(&path_str))?;
+    Ok(())
+});
+
+api!(extract_afb(
+    in_path: *const Char,
+    out_folder: *const Char
+) {
+    check_null_ptr!(in_path);
+    check_null_ptr!(out_folder);
+
+    let in_path_str = ffi_to_string(in_path)?;
+    let out_folder_str = ffi_to_string(out_folder)?;
+
+    img::extract_afb(Path::new(&in_path_str), &out_folder_str)?;
+    Ok(())
+});
+
+api!(convert_stage(
+    bg_in_path: *const Char,
+    fx_in_paths: *const *const Char,
+    fx_in_paths_count: c_int,
+    st_out_path: *const Char,
+    nf_out_path: *const Char
+) {
+    check_null_ptr!(bg_in_path);
+    check_null_ptr!(st_out_path);
+    check_null_ptr!(nf_out_path);
+    if fx_in_paths.is_null() && fx_in_paths_count > 0 {
+        bail_code!(
+            ErrorCode::InvalidArgument,
+            "NULL received for fx_in_paths while count is greater than 0"
+        );
+    }
+
+    let in_path_str = ffi_to_string(bg_in_path)?;
+    let fx_path_vec = ffi_arr_to_vec(fx_in_paths, fx_in_paths_count)?;
+    let st_out_path_str = ffi_to_string(st_out_path)?;
+    let nf_out_path_str = ffi_to_string(nf_out_path)?;
+
+    let fx_in_paths: Vec<Option<&Path>> = fx_path_vec
+        .iter()
+        .map(|opt_str| opt_str.as_ref().map(Path::new))
+        .collect();
+
+    img::convert_stage(
+        Path::new(in_path_str.as_str()),
+        &fx_in_paths,
+        Path::new(st_out_path_str.as_str()),
+        Path::new(nf_out_path_str.as_str()),
+    )?;
+    Ok(())
+});
+
+api!(mua_convert_stage_ex(options: *const StageOptions<Char>) {
+    let options = read_sized_struct(options, STAGE_OPTIONS_V1_SIZE)?;
+    check_null_ptr!(options.st_out_path);
+    check_null_ptr!(options.nf_out_path);
+
+    let mut builder = stage_builder_from_raw(
+        options.bg_in_path,
+        options.fx_in_paths,
+        options.fx_in_paths_count,
+        options.bg_format,
+    )?
+    .decode(decode_options_from_raw(
+        img::DecodeOptions::new(),
+        options.input_flags,
+        options.tone_mapping,
+        options.exposure,
+        options.frame_selection,
+        options.frame_value,
+    )?)
+    .progress(progress_from_raw(
+        options.progress_callback,
+        options.progress_user_data,
+        options.cancel_token,
+    ));
+    if !options.fx_animation_path.is_null() {
+        builder = builder.fx_animation(ffi_to_string(options.fx_animation_path)?);
+    }
+    let st_out_path_str = ffi_to_string(options.st_out_path)?;
+    let nf_out_path_str = ffi_to_string(options.nf_out_path)?;
+
+    builder.build_to(
+        Path::new(st_out_path_str.as_str()),
+        Path::new(nf_out_path_str.as_str()),
+    )?;
+    Ok(())
+});
+
+api!(mua_build_stage_package(options: *const StagePackageOptions<Char>) {
+    let options = read_sized_struct(options, STAGE_PACKAGE_OPTIONS_V1_SIZE)?;
+    check_null_ptr!(options.out_folder);
+    check_null_ptr!(options.stage_name);
+
+    let mut builder = stage_builder_from_raw(
+        options.bg_in_path,
+        options.fx_in_paths,
+        options.fx_in_paths_count,
+        options.bg_format,
+    )?
+    .decode(decode_options_from_raw(
+        img::DecodeOptions::new(),
+        options.input_flags,
+        options.tone_mapping,
+        options.exposure,
+        options.frame_selection,
+        options.frame_value,
+    )?)
+    .progress(progress_from_raw(
+        options.progress_callback,
+        options.progress_user_data,
+        options.cancel_token,
+    ));
+    if !options.fx_animation_path.is_null() {
+        builder = builder.fx_animation(ffi_to_string(options.fx_animation_path)?);
+    }
+    let out_folder_str = ffi_to_string(options.out_folder)?;
+
+    let mut info = img::StageInfo::new(options.stage_id, ffi_to_string(options.stage_name)?);
+    if options.notes_field_line_name.is_null() {
+        if options.notes_field_line_id != 0 {
+            bail_code!(
+                ErrorCode::InvalidArgument,
+                "notes_field_line_id is set but notes_field_line_name is NULL"
+            );
+        }
+    } else {
+        info = info.notes_field_line(
+            options.notes_field_line_id,
+            ffi_to_string(options.notes_field_line_name)?,
+        );
+    }
+
+    img::build_stage_package(&builder, &info, Path::new(&out_folder_str))?;
+    Ok(())
+});
+
+api!(convert_jk(
+    in_path: *const Char,
+    out_path: *const Char
+) {
+    check_null_ptr!(in_path);
+    check_null_ptr!(out_path);
+
+    let in_path_str = ffi_to_string(in_path)?;
+    let out_path_str = ffi_to_string(out_path)?;
+
+    img::convert_jk_file(
+        Path::new(&in_path_str),
+        Path::new(&out_path_str),
+        &img::DecodeOptions::default(),
+        &img::Progress::none(),
+    )?;
+    Ok(())
+});
+
+api!(mua_convert_jk_ex(options: *const JacketOptions<Char>) {
+    convert_jk_from_raw(&read_sized_struct(options, JACKET_OPTIONS_V1_SIZE)?)
+});
+
+api!(mua_convert_atlas_ex(options: *const AtlasOptions<Char>) {
+    convert_atlas_from_raw(&read_sized_struct(options, ATLAS_OPTIONS_V1_SIZE)?)
+});
+
+api!(mua_build_jacket_package(
+    in_path: *const Char,
+    music_id: u32,
+    out_folder: *const Char,
+    music_xml_path: *const Char
+) {
+    check_null_ptr!(in_path);
+    check_null_ptr!(out_folder);
+
+    let in_path_str = ffi_to_string(in_path)?;
+    let out_folder_str = ffi_to_string(out_folder)?;
+    let music_xml_str = if music_xml_path.is_null() {
+        None
+    } else {
+        Some(ffi_to_string(music_xml_path)?)
+    };
+
+    img::build_jacket_package(
+        Path::new(&in_path_str),
+        music_id,
+        Path::new(&out_folder_str),
+        music_xml_str.as_deref().map(Path::new),
+    )?;
+    Ok(())
+});
+
+api!(mua_build_jacket_package_ex(options: *const JacketPackageOptions<Char>) {
+    let options = read_sized_struct(options, JACKET_PACKAGE_OPTIONS_V1_SIZE)?;
+    check_null_ptr!(options.in_path);
+    check_null_ptr!(options.out_folder);
+
+    let in_path_str = ffi_to_string(options.in_path)?;
+    let out_folder_str = ffi_to_string(options.out_folder)?;
+    let music_xml_str = if options.music_xml_path.is_null() {
+        None
+    } else {
+        Some(ffi_to_string(options.music_xml_path)?)
+    };
+    let decode = decode_options_from_raw(
+        img::DecodeOptions::new(),
+        options.input_flags,
+        options.tone_mapping,
+        options.exposure,
+        options.frame_selection,
+        options.frame_value,
+    )?;
+
+    img::build_jacket_package_with_options(
+        Path::new(&in_path_str),
+        options.music_id,
+        Path::new(&out_folder_str),
+        music_xml_str.as_deref().map(Path::new),
+        &decode,
+        &progress_from_raw(
+            options.progress_callback,
+            options.progress_user_data,
+            options.cancel_token,
+        ),
+    )?;
+    Ok(())
+});
+
+api!(mua_convert_preset(
+    preset_name: *const Char,
+    in_path: *const Char,
+    out_path: *const Char
+) {
+    check_null_ptr!(preset_name);
+    check_null_ptr!(in_path);
+    check_null_ptr!(out_path);
+
+    let preset_name_str = ffi_to_string(preset_name)?;
+    let in_path_str = ffi_to_string(in_path)?;
+    let out_path_str = ffi_to_string(out_path)?;
+
+    img::convert_preset(&preset_name_str, Path::new(&in_path_str), Path::new(&out_path_str))?;
+    Ok(())
+});
+
+api!(mua_convert_preset_ex(options: *const PresetOptions<Char>) {
+    let options = read_sized_struct(options, PRESET_OPTIONS_V1_SIZE)?;
+    check_null_ptr!(options.preset_name);
+    check_null_ptr!(options.in_path);
+    check_null_ptr!(options.out_path);
+
+    let preset_name_str = ffi_to_string(options.preset_name)?;
+    let in_path_str = ffi_to_string(options.in_path)?;
+    let out_path_str = ffi_to_string(options.out_path)?;
+    let mut preset = img::get_preset(&preset_name_str)?;
+    preset.decode = decode_options_from_raw(
+        preset.decode,
+        options.input_flags,
+        options.tone_mapping,
+        options.exposure,
+        options.frame_selection,
+        options.frame_value,
+    )?;
+
+    img::convert_preset_file(
+        &preset,
+        Path::new(&in_path_str),
+        Path::new(&out_path_str),
+        &progress_from_raw(
+            options.progress_callback,
+            options.progress_user_data,
+            options.cancel_token,
+        ),
+    )?;
+    Ok(())
+});
+
+api!(mua_load_presets(in_path: *const Char) {
+    check_null_ptr!(in_path);
+    let path_str = ffi_to_string(in_path)?;
+    img::load_presets(Path::new(&path_str))?;
+    Ok(())
+});
+
+// Caches jacket, stage and preset conversions in `cache_dir`, keyed on the input bytes, the
+// conversion parameters and the library version. NULL disables the cache.
+api!(mua_set_cache_dir(cache_dir: *const Char) {
+    if cache_dir.is_null() {
+        img::set_cache_dir(None)?;
+    } else {
+        let cache_dir_str = ffi_to_string(cache_dir)?;
+        img::set_cache_dir(Some(Path::new(&cache_dir_str)))?;
+    }
+    Ok(())
+});
+
+// Removes the least recently used cache entries until at most `max_bytes` remain, and every
+// entry unused for longer than `max_age_seconds` (0 = no age limit).
+api!(mua_prune_cache(max_bytes: u64, max_age_seconds: u64) {
+    let max_age = (max_age_seconds > 0).then(|| Duration::from_secs(max_age_seconds));
+    img::prune_cache(max_bytes, max_age)?;
+    Ok(())
+});
+
+api!(mua_run_batch(
+    jobs: *const BatchJob<Char>,
+    job_count: c_int,
+    thread_count: c_int,
+    job_results: *mut c_int,
+    job_error_buffers: *const *mut Char,
+    job_error_buffer_size: c_int
+) {
+    run_batch_from_raw(&BatchOptions {
+        struct_size: size_of::<BatchOptions<Char>>() as u32,
+        jobs,
+        job_count,
+        thread_count,
+        job_results,
+        job_error_buffers,
+        job_error_buffer_size,
+        cancel_token: std::ptr::null(),
+    })
+});
+
+api!(mua_run_batch_ex(options: *const BatchOptions<Char>) {
+    let options = read_sized_struct(options, BATCH_OPTIONS_V1_SIZE)?;
+    run_batch_from_raw(&options)
+});
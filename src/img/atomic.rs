@@ -0,0 +1,119 @@
+use crate::img::error::{Error, Result};
+use log::info;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Stages output files as temporaries next to their targets and moves them into place together
+/// on `commit`. Temporaries that were never committed are removed on drop, so a failed or
+/// cancelled operation leaves the existing outputs untouched.
+#[derive(Default)]
+pub struct AtomicWriter {
+    pending: Vec<(PathBuf, PathBuf)>,
+}
+
+impl AtomicWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the temporary file standing in for `path`.
+    pub fn create(&mut self, path: &Path) -> Result<File> {
+        let tmp_path = sibling_path(path, "tmp");
+        let file = File::create(&tmp_path).map_err(Error::io(&tmp_path))?;
+        self.pending.push((tmp_path, path.to_path_buf()));
+        Ok(file)
+    }
+
+    pub fn write(&mut self, path: &Path, data: &[u8]) -> Result<()> {
+        let tmp_path = sibling_path(path, "tmp");
+        self.pending.push((tmp_path.clone(), path.to_path_buf()));
+        fs::write(&tmp_path, data).map_err(Error::io(&tmp_path))
+    }
+
+    /// Renames every staged file over its target. If any rename fails, the targets already
+    /// replaced are restored from their backups so either all outputs change or none do.
+    pub fn commit(mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        let mut committed: Vec<(&Path, Option<PathBuf>)> = Vec::new();
+
+        let result = pending
+            .iter()
+            .try_for_each(|(tmp_path, target)| -> Result<()> {
+                let backup = if target.exists() {
+                    let backup = sibling_path(target, "bak");
+                    fs::rename(target, &backup).map_err(Error::io(target))?;
+                    Some(backup)
+                } else {
+                    None
+                };
+
+                if let Err(err) = fs::rename(tmp_path, target) {
+                    if let Some(backup) = &backup {
+                        _ = fs::rename(backup, target);
+                    }
+                    return Err(Error::io(target)(err));
+                }
+                if let Ok(metadata) = fs::metadata(target) {
+                    info!("Wrote {} ({} bytes)", target.display(), metadata.len());
+                }
+                committed.push((target, backup));
+                Ok(())
+            });
+
+        if result.is_err() {
+            for (target, backup) in committed.into_iter().rev() {
+                match backup {
+                    Some(backup) => {
+                        _ = fs::rename(backup, target);
+                    }
+                    None => {
+                        _ = fs::remove_file(target);
+                    }
+                }
+            }
+            for (tmp_path, _) in &pending {
+                _ = fs::remove_file(tmp_path);
+            }
+            return result;
+        }
+
+        for (_, backup) in committed {
+            if let Some(backup) = backup {
+                _ = fs::remove_file(backup);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicWriter {
+    fn drop(&mut self) {
+        for (tmp_path, _) in &self.pending {
+            _ = fs::remove_file(tmp_path);
+        }
+    }
+}
+
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut writer = AtomicWriter::new();
+    writer.write(path, data)?;
+    writer.commit()
+}
+
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let file_name = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output");
+    let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(
+        ".{}.{}.{}.{}",
+        file_name,
+        std::process::id(),
+        unique,
+        extension
+    ))
+}
@@ -0,0 +1,92 @@
+use crate::img::convert::convert_jk_file;
+use crate::img::decode::DecodeOptions;
+use crate::img::error::{Error, Result};
+use crate::img::preset::{Preset, convert_preset_file};
+use crate::img::progress::{CancelToken, Progress};
+use crate::img::stage::StageBuilder;
+use crate::img::utils::panic_message;
+use rayon::prelude::*;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::PathBuf;
+
+/// A single conversion run by `run_batch`.
+pub enum Job {
+    Jacket {
+        in_path: PathBuf,
+        out_path: PathBuf,
+        decode: DecodeOptions,
+    },
+    Stage {
+        stage: StageBuilder,
+        st_out_path: PathBuf,
+        nf_out_path: PathBuf,
+    },
+    /// Converts with `preset`, usually looked up with `get_preset`.
+    Preset {
+        preset: Preset,
+        in_path: PathBuf,
+        out_path: PathBuf,
+    },
+}
+
+impl Job {
+    pub fn run(&self) -> Result<()> {
+        self.run_with_cancel(&CancelToken::new())
+    }
+
+    /// Like `run`, but stops with `Error::Cancelled` at the next checkpoint once `cancel` is
+    /// cancelled. A stage keeps its own progress callback but uses `cancel` as its token.
+    pub fn run_with_cancel(&self, cancel: &CancelToken) -> Result<()> {
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let progress = Progress::none().cancel_token(cancel.clone());
+        match self {
+            Job::Jacket {
+                in_path,
+                out_path,
+                decode,
+            } => convert_jk_file(in_path, out_path, decode, &progress),
+            Job::Stage {
+                stage,
+                st_out_path,
+                nf_out_path,
+            } => stage
+                .with_cancel_token(cancel.clone())
+                .build_to(st_out_path, nf_out_path),
+            Job::Preset {
+                preset,
+                in_path,
+                out_path,
+            } => convert_preset_file(preset, in_path, out_path, &progress),
+        }
+    }
+}
+
+/// Runs every job on a worker pool of `threads` threads (0 picks one per CPU).
+/// A failing or panicking job does not stop the others; results are returned in job order.
+pub fn run_batch(jobs: &[Job], threads: usize) -> Result<Vec<Result<()>>> {
+    run_batch_with_cancel(jobs, threads, &CancelToken::new())
+}
+
+/// Like `run_batch`. Once `cancel` is cancelled, running jobs stop at their next checkpoint and
+/// the remaining ones fail with `Error::Cancelled` without touching their outputs.
+pub fn run_batch_with_cancel(
+    jobs: &[Job],
+    threads: usize,
+    cancel: &CancelToken,
+) -> Result<Vec<Result<()>>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(Error::ThreadPool)?;
+    Ok(pool.install(|| {
+        jobs.par_iter()
+            .map(|job| {
+                catch_unwind(AssertUnwindSafe(|| job.run_with_cancel(cancel))).unwrap_or_else(
+                    |payload| Err(Error::Panic(panic_message(payload.as_ref()).to_string())),
+                )
+            })
+            .collect()
+    }))
+}
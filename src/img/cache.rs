@@ -0,0 +1,277 @@
+use crate::capabilities::VERSION;
+use crate::img::atomic::{AtomicWriter, write_atomic};
+use crate::img::error::{Error, Result};
+use log::{debug, info, warn};
+use std::cmp::Reverse;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+static CACHE: RwLock<Option<ConversionCache>> = RwLock::new(None);
+
+/// Age after which `prune` treats a temporary file as left behind by an interrupted write
+/// rather than one still in progress.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Directory of converted DDS and AFB outputs, each stored under the hash of its inputs and
+/// conversion parameters so unchanged assets are not encoded again.
+#[derive(Debug, Clone)]
+pub struct ConversionCache {
+    dir: PathBuf,
+}
+
+/// What `ConversionCache::prune` removed and kept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PruneStats {
+    pub removed_entries: usize,
+    pub removed_bytes: u64,
+    pub kept_entries: usize,
+    pub kept_bytes: u64,
+}
+
+impl ConversionCache {
+    /// Opens the cache in `dir`, creating the directory if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(Error::io(&dir))?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Removes the least recently used entries until at most `max_bytes` remain, along with
+    /// every entry not used within `max_age` and temporary files of interrupted writes.
+    pub fn prune(&self, max_bytes: u64, max_age: Option<Duration>) -> Result<PruneStats> {
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir).map_err(Error::io(&self.dir))? {
+            let entry = entry.map_err(Error::io(&self.dir))?;
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+            let is_entry = is_entry_name(name);
+            if !is_entry && !is_temp_name(name) {
+                continue;
+            }
+            let metadata = entry.metadata().map_err(Error::io(&entry.path()))?;
+            let last_used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            if !is_entry {
+                if now
+                    .duration_since(last_used)
+                    .is_ok_and(|age| age > STALE_TEMP_AGE)
+                {
+                    let path = entry.path();
+                    match fs::remove_file(&path) {
+                        Ok(()) => debug!("Removed stale temporary file {}", path.display()),
+                        // Another prune of the same directory got to it first.
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        Err(err) => return Err(Error::io(&path)(err)),
+                    }
+                }
+                continue;
+            }
+            entries.push((last_used, metadata.len(), entry.path()));
+        }
+        entries.sort_by_key(|&(last_used, ..)| Reverse(last_used));
+
+        let mut stats = PruneStats::default();
+        let mut over_budget = false;
+        for (last_used, size, path) in entries {
+            let expired = max_age.is_some_and(|max_age| {
+                now.duration_since(last_used).is_ok_and(|age| age > max_age)
+            });
+            over_budget |= stats.kept_bytes + size > max_bytes;
+            if expired || over_budget {
+                fs::remove_file(&path).map_err(Error::io(&path))?;
+                stats.removed_entries += 1;
+                stats.removed_bytes += size;
+            } else {
+                stats.kept_entries += 1;
+                stats.kept_bytes += size;
+            }
+        }
+        info!(
+            "Pruned {} cache entries ({} bytes), kept {} ({} bytes)",
+            stats.removed_entries, stats.removed_bytes, stats.kept_entries, stats.kept_bytes
+        );
+        Ok(stats)
+    }
+
+    /// Returns the output stored under `key` and marks it as recently used.
+    pub(crate) fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let (mut file, path) = self.open(key)?;
+        let mut data = Vec::new();
+        match file.read_to_end(&mut data) {
+            Ok(_) => Some(data),
+            Err(err) => {
+                warn!(
+                    "Ignoring unreadable cache entry {}: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Opens the output stored under `key` for streaming and marks it as recently used.
+    pub(crate) fn open(&self, key: &CacheKey) -> Option<(File, PathBuf)> {
+        let path = self.dir.join(key.hex());
+        let file = match open_entry(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!(
+                    "Ignoring unreadable cache entry {}: {}",
+                    path.display(),
+                    err
+                );
+                return None;
+            }
+        };
+        // The modification time orders entries for `prune`. Entries owned by someone else in
+        // a shared cache can't be touched, so a failure still counts as a hit.
+        if let Err(err) = file.set_modified(SystemTime::now()) {
+            debug!("Could not touch cache entry {}: {}", path.display(), err);
+        }
+        debug!("Cache hit {}", path.display());
+        Some((file, path))
+    }
+
+    /// Stores `data` under `key`. Failures are logged and otherwise ignored; the conversion
+    /// that produced `data` has already succeeded.
+    pub(crate) fn put(&self, key: &CacheKey, data: &[u8]) {
+        let path = self.dir.join(key.hex());
+        match write_atomic(&path, data) {
+            Ok(()) => debug!("Cached {}", path.display()),
+            Err(err) => warn!("Failed to cache {}: {}", path.display(), err),
+        }
+    }
+
+    /// Like `put`, but copies the output from the file at `source`.
+    pub(crate) fn put_file(&self, key: &CacheKey, source: &Path) {
+        let path = self.dir.join(key.hex());
+        let copy = || -> Result<()> {
+            let mut writer = AtomicWriter::new();
+            let mut file = writer.create(&path)?;
+            let mut source_file = File::open(source).map_err(Error::io(source))?;
+            io::copy(&mut source_file, &mut file).map_err(Error::io(&path))?;
+            drop(file);
+            writer.commit()
+        };
+        match copy() {
+            Ok(()) => debug!("Cached {}", path.display()),
+            Err(err) => warn!("Failed to cache {}: {}", path.display(), err),
+        }
+    }
+}
+
+/// Opens a cache entry for reading. Unix lets the owner set the times through a read-only
+/// handle; Windows needs `FILE_WRITE_ATTRIBUTES`, which is requested when the entry allows it.
+fn open_entry(path: &Path) -> io::Result<File> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        const GENERIC_READ: u32 = 0x8000_0000;
+        const FILE_WRITE_ATTRIBUTES: u32 = 0x0100;
+        match File::options()
+            .access_mode(GENERIC_READ | FILE_WRITE_ATTRIBUTES)
+            .open(path)
+        {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+            result => return result,
+        }
+    }
+    File::open(path)
+}
+
+/// Entries are named after the 64-digit hex hash of their key.
+fn is_entry_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Temporary files are staged by `AtomicWriter` as `.<entry>.<pid>.<n>.tmp`.
+fn is_temp_name(name: &str) -> bool {
+    name.strip_prefix('.')
+        .and_then(|name| name.strip_suffix(".tmp"))
+        .and_then(|name| name.split('.').next())
+        .is_some_and(is_entry_name)
+}
+
+/// Sets the directory used to cache conversions, or disables caching with `None`.
+pub fn set_cache_dir(dir: Option<&Path>) -> Result<()> {
+    let cache = dir.map(ConversionCache::new).transpose()?;
+    *CACHE.write().map_err(|_| Error::Poisoned)? = cache;
+    Ok(())
+}
+
+/// Prunes the cache set with `set_cache_dir`; does nothing when caching is disabled.
+pub fn prune_cache(max_bytes: u64, max_age: Option<Duration>) -> Result<PruneStats> {
+    match active_cache()? {
+        Some(cache) => cache.prune(max_bytes, max_age),
+        None => Ok(PruneStats::default()),
+    }
+}
+
+pub(crate) fn active_cache() -> Result<Option<ConversionCache>> {
+    Ok(CACHE.read().map_err(|_| Error::Poisoned)?.clone())
+}
+
+/// Returns the output cached under the key built by `key`, or runs `convert` and caches its
+/// result. The key is only computed when a cache is set.
+pub(crate) fn cached(
+    key: impl FnOnce() -> Result<CacheKey>,
+    convert: impl FnOnce() -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let Some(cache) = active_cache()? else {
+        return convert();
+    };
+    let key = key()?;
+    if let Some(data) = cache.get(&key) {
+        return Ok(data);
+    }
+    let data = convert()?;
+    cache.put(&key, &data);
+    Ok(data)
+}
+
+/// Hash identifying one conversion: the library version, the kind of output, the contents of
+/// every input file and the `Debug` form of every parameter.
+pub(crate) struct CacheKey {
+    hasher: blake3::Hasher,
+}
+
+impl CacheKey {
+    pub(crate) fn new(kind: &str) -> Self {
+        let mut key = Self {
+            hasher: blake3::Hasher::new(),
+        };
+        key.param(&VERSION).param(&kind);
+        key
+    }
+
+    pub(crate) fn input(&mut self, path: &Path) -> Result<&mut Self> {
+        let mut file = File::open(path).map_err(Error::io(path))?;
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut file, &mut hasher).map_err(Error::io(path))?;
+        self.hasher.update(hasher.finalize().as_bytes());
+        Ok(self)
+    }
+
+    pub(crate) fn param(&mut self, value: &dyn Debug) -> &mut Self {
+        let text = format!("{:?}", value);
+        self.hasher.update(blake3::hash(text.as_bytes()).as_bytes());
+        self
+    }
+
+    pub(crate) fn hex(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
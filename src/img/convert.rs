@@ -1,130 +1,436 @@
-use crate::img::assets::{FX_DUMMY, NF_DUMMY, ST_CHUNKS, ST_DUMMY};
-use crate::img::locate::{extract_chunks, locate_dds_chunks, replace_chunks};
+use crate::img::atomic::{AtomicWriter, write_atomic};
+use crate::img::cache::{CacheKey, cached};
+use crate::img::decode::{DecodeOptions, open_frames, open_image};
+use crate::img::error::{Error, Result};
+use crate::img::locate::{
+    Payload, SignatureRegistry, extract_payloads, iter_payloads, locate_payloads, map_file,
+    replace_chunks,
+};
+use crate::img::progress::{Progress, Step};
+use crate::img::quantize::to_rgba8;
+use crate::img::stage::{FX_SLOTS, StageBuilder};
 use crate::img::utils::{compress_image, resize_if_needed, save_dds_blob};
 use directxtex::{DXGI_FORMAT, ScratchImage};
-use image::imageops::FilterType;
-use image::{ImageBuffer, Rgba};
+use image::{DynamicImage, RgbaImage, imageops};
+use log::debug;
+use memmap2::Mmap;
 use std::fs;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 
+const FX_TILE: u32 = 256;
+const FX_CANVAS: u32 = FX_TILE * 2;
+/// Largest 2D texture side Direct3D 11 accepts, bounding both sides of an atlas.
+const MAX_ATLAS_WIDTH: u32 = 16384;
+
+/// Decodes `in_path`, resizes it to `width`x`height` and encodes it as `format`.
 pub fn convert_dds(
     in_path: &Path,
     width: u32,
     height: u32,
     format: DXGI_FORMAT,
-) -> anyhow::Result<ScratchImage> {
+) -> Result<ScratchImage> {
+    convert_dds_with_progress(in_path, width, height, format, &Progress::none())
+}
+
+pub fn convert_dds_with_progress(
+    in_path: &Path,
+    width: u32,
+    height: u32,
+    format: DXGI_FORMAT,
+    progress: &Progress,
+) -> Result<ScratchImage> {
+    let decode = DecodeOptions::default();
+    convert_dds_with_options(in_path, width, height, format, &decode, progress)
+}
+
+/// Like `convert_dds_with_progress`, with control over how the source image is decoded.
+pub fn convert_dds_with_options(
+    in_path: &Path,
+    width: u32,
+    height: u32,
+    format: DXGI_FORMAT,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<ScratchImage> {
     if width == 0 || height == 0 {
-        anyhow::bail!("Invalid dimensions: width and height must be greater than 0")
+        return Err(Error::InvalidDimensions { width, height });
     }
 
-    let rgba_image = image::open(in_path)?.into_rgba8();
-    let (orig_width, orig_height) = rgba_image.dimensions();
+    progress.checkpoint(Step::Decoding, 0.0)?;
+    let img = open_image(in_path, decode)?;
 
-    let processed = if width != orig_width || height != orig_height {
-        image::imageops::resize(&rgba_image, width, height, FilterType::Lanczos3)
-    } else {
-        rgba_image
-    };
+    progress.checkpoint(Step::Resizing, 0.25)?;
+    let resized = resize_if_needed(img, width, height, &progress.range(0.25, 0.5))?;
+    let processed = to_rgba8(resized, decode);
 
+    progress.checkpoint(Step::Compressing, 0.5)?;
     let (width, height) = processed.dimensions();
     let mut pixel_vec = processed.into_raw();
-    compress_image(width, height, format, &mut pixel_vec)
+    let dds = compress_image(
+        width,
+        height,
+        format,
+        &mut pixel_vec,
+        &progress.range(0.5, 1.0),
+    )?;
+    progress.checkpoint(Step::Compressing, 1.0)?;
+    Ok(dds)
 }
 
-pub fn convert_bg(in_path: &Path) -> anyhow::Result<ScratchImage> {
+/// Converts a 1920x1080 BC1 stage background.
+pub fn convert_bg(in_path: &Path) -> Result<ScratchImage> {
     const FORMAT: DXGI_FORMAT = DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM;
     convert_dds(in_path, 1920, 1080, FORMAT)
 }
 
-pub fn convert_jk(in_path: &Path) -> anyhow::Result<ScratchImage> {
+/// Converts a 300x300 BC1 jacket.
+pub fn convert_jk(in_path: &Path) -> Result<ScratchImage> {
+    convert_jk_with_progress(in_path, &Progress::none())
+}
+
+pub fn convert_jk_with_progress(in_path: &Path, progress: &Progress) -> Result<ScratchImage> {
+    convert_jk_with_options(in_path, &DecodeOptions::default(), progress)
+}
+
+pub fn convert_jk_with_options(
+    in_path: &Path,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<ScratchImage> {
     const FORMAT: DXGI_FORMAT = DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM;
-    convert_dds(in_path, 300, 300, FORMAT)
+    convert_dds_with_options(in_path, 300, 300, FORMAT, decode, progress)
 }
 
-pub fn convert_fx(in_paths: &[Option<&Path>]) -> anyhow::Result<ScratchImage> {
-    const TILE: u32 = 256;
-    const CANVAS: u32 = TILE * 2;
+/// Converts a jacket and writes it to `out_path`, reusing the conversion cache when one is set.
+pub fn convert_jk_file(
+    in_path: &Path,
+    out_path: &Path,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<()> {
+    let dds = jacket_dds(in_path, decode, &progress.range(0.0, 0.9))?;
+    progress.checkpoint(Step::Writing, 0.9)?;
+    write_atomic(out_path, &dds)?;
+    progress.report(Step::Writing, 1.0);
+    Ok(())
+}
 
-    let mut output_buffer = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(CANVAS, CANVAS);
-    let mut count = 0;
+/// The DDS file of a jacket, from the conversion cache when one is set.
+pub(crate) fn jacket_dds(
+    in_path: &Path,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<Vec<u8>> {
+    cached(
+        || {
+            let mut key = CacheKey::new("jacket");
+            key.input(in_path)?.param(decode);
+            Ok(key)
+        },
+        || {
+            let dds = convert_jk_with_options(in_path, decode, progress)?;
+            Ok(save_dds_blob(dds)?.buffer().to_vec())
+        },
+    )
+}
 
-    for input_path_opt in in_paths.iter().take(4) {
-        let input_path = match input_path_opt {
-            Some(path) => path,
-            None => continue,
-        };
+/// Tiles up to four FX images (256x256 each) into a 512x512 BC3 texture, skipping empty slots.
+pub fn convert_fx(in_paths: &[Option<&Path>]) -> Result<ScratchImage> {
+    convert_fx_with_progress(in_paths, &Progress::none())
+}
 
-        let img = image::open(input_path)?;
-
-        let img = resize_if_needed(img, TILE, TILE);
-        let img = img.to_rgba8();
-        let pixels = img.as_raw();
-
-        let i = count as u32;
-        let offset_x = (i % 2) * TILE;
-        let offset_y = (i / 2) * TILE;
-
-        for y in 0..TILE {
-            let out_y = offset_y + y;
-            for x in 0..TILE {
-                let out_x = offset_x + x;
-                let in_idx = ((y * TILE + x) * 4) as usize;
-                let pixel = Rgba([
-                    pixels[in_idx],
-                    pixels[in_idx + 1],
-                    pixels[in_idx + 2],
-                    pixels[in_idx + 3],
-                ]);
-                output_buffer.put_pixel(out_x, out_y, pixel);
-            }
-        }
+pub fn convert_fx_with_progress(
+    in_paths: &[Option<&Path>],
+    progress: &Progress,
+) -> Result<ScratchImage> {
+    convert_fx_with_options(in_paths, &DecodeOptions::default(), progress)
+}
 
-        count += 1;
-        if count >= 4 {
-            break;
-        }
+/// Like `convert_fx_with_progress`, with control over how the FX images are decoded.
+pub fn convert_fx_with_options(
+    in_paths: &[Option<&Path>],
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<ScratchImage> {
+    let packed: Vec<Option<&Path>> = in_paths
+        .iter()
+        .take(FX_SLOTS)
+        .flatten()
+        .map(|p| Some(*p))
+        .collect();
+    convert_fx_slots(&packed, decode, progress)
+}
+
+/// Tiles the FX image of each slot into its own 256x256 tile of a 512x512 BC3 texture, leaving
+/// the tiles of empty slots transparent.
+pub(crate) fn convert_fx_slots(
+    slots: &[Option<&Path>],
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<ScratchImage> {
+    let canvas = fx_canvas(slots, decode, progress)?;
+    compress_fx(canvas, progress)
+}
+
+pub(crate) fn fx_canvas(
+    slots: &[Option<&Path>],
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<RgbaImage> {
+    let mut output_buffer = RgbaImage::new(FX_CANVAS, FX_CANVAS);
+    let tiles = slots.iter().flatten().count().max(1) as f32;
+    for (count, (slot, input_path)) in slots
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, path)| path.map(|path| (slot, path)))
+        .enumerate()
+    {
+        let tile_progress =
+            progress.range(count as f32 / tiles * 0.5, (count + 1) as f32 / tiles * 0.5);
+        tile_progress.checkpoint(Step::Decoding, 0.0)?;
+        let img = open_image(input_path, decode)?;
+
+        tile_progress.checkpoint(Step::Resizing, 0.5)?;
+        place_fx_tile(
+            &mut output_buffer,
+            slot,
+            img,
+            decode,
+            &tile_progress.range(0.5, 1.0),
+        )?;
+    }
+    Ok(output_buffer)
+}
+
+/// Tiles the first four frames of the animation at `in_path` into a 512x512 BC3 FX texture.
+/// Later frames are not decoded, and a still image fills the first tile only.
+pub fn convert_fx_animation(
+    in_path: &Path,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<ScratchImage> {
+    progress.checkpoint(Step::Decoding, 0.0)?;
+    let frames = open_frames(in_path, decode)?
+        .take(FX_SLOTS)
+        .collect::<Result<Vec<_>>>()?;
+
+    progress.checkpoint(Step::Resizing, 0.25)?;
+    let tiles = frames.len();
+    let mut output_buffer = RgbaImage::new(FX_CANVAS, FX_CANVAS);
+    for (slot, frame) in frames.into_iter().enumerate() {
+        let tile_progress = progress.range(
+            0.25 + slot as f32 / tiles as f32 * 0.25,
+            0.25 + (slot + 1) as f32 / tiles as f32 * 0.25,
+        );
+        place_fx_tile(&mut output_buffer, slot, frame, decode, &tile_progress)?;
     }
+    compress_fx(output_buffer, progress)
+}
+
+fn place_fx_tile(
+    canvas: &mut RgbaImage,
+    slot: usize,
+    img: DynamicImage,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<()> {
+    let tile = to_rgba8(resize_if_needed(img, FX_TILE, FX_TILE, progress)?, decode);
+    let slot = slot as u32;
+    let offset_x = (slot % 2) * FX_TILE;
+    let offset_y = (slot / 2) * FX_TILE;
+    imageops::replace(canvas, &tile, offset_x as i64, offset_y as i64);
+    Ok(())
+}
 
-    let mut pixel_data = output_buffer.into_raw();
-    compress_image(
-        CANVAS,
-        CANVAS,
+fn compress_fx(canvas: RgbaImage, progress: &Progress) -> Result<ScratchImage> {
+    progress.checkpoint(Step::Compressing, 0.5)?;
+    let mut pixel_data = canvas.into_raw();
+    let dds = compress_image(
+        FX_CANVAS,
+        FX_CANVAS,
         DXGI_FORMAT::DXGI_FORMAT_BC3_UNORM,
         &mut pixel_data,
-    )
+        &progress.range(0.5, 1.0),
+    )?;
+    progress.checkpoint(Step::Compressing, 1.0)?;
+    Ok(dds)
 }
 
-pub fn extract_afb(in_path: &Path, out_folder: &str) -> anyhow::Result<()> {
-    let data = fs::read(in_path)?;
-    let chunks = locate_dds_chunks(&data);
-    if chunks.is_empty() {
-        anyhow::bail!("No .dds chunks found in the file");
+/// Lays every frame of the animation at `in_path` out left to right in a single strip of
+/// `frame_width`x`frame_height` cells and encodes it as `format`. Fails as soon as the strip
+/// would be wider than 16384 pixels, without decoding the remaining frames.
+pub fn convert_atlas(
+    in_path: &Path,
+    frame_width: u32,
+    frame_height: u32,
+    format: DXGI_FORMAT,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<ScratchImage> {
+    if frame_width == 0
+        || frame_height == 0
+        || frame_width > MAX_ATLAS_WIDTH
+        || frame_height > MAX_ATLAS_WIDTH
+    {
+        return Err(Error::InvalidDimensions {
+            width: frame_width,
+            height: frame_height,
+        });
     }
+
+    progress.checkpoint(Step::Decoding, 0.0)?;
+    let max_frames = (MAX_ATLAS_WIDTH / frame_width) as usize;
+    let mut frames = Vec::new();
+    for frame in open_frames(in_path, decode)? {
+        if frames.len() == max_frames {
+            return Err(Error::InvalidDimensions {
+                width: (max_frames as u32 + 1) * frame_width,
+                height: frame_height,
+            });
+        }
+        frames.push(frame?);
+    }
+    let width = frames.len() as u32 * frame_width;
+
+    progress.checkpoint(Step::Resizing, 0.25)?;
+    let mut atlas = RgbaImage::new(width, frame_height);
+    let cells = frames.len() as f32;
+    for (i, frame) in frames.into_iter().enumerate() {
+        let cell_progress = progress.range(
+            0.25 + i as f32 / cells * 0.25,
+            0.25 + (i + 1) as f32 / cells * 0.25,
+        );
+        let cell = resize_if_needed(frame, frame_width, frame_height, &cell_progress)?;
+        imageops::replace(
+            &mut atlas,
+            &to_rgba8(cell, decode),
+            i as i64 * frame_width as i64,
+            0,
+        );
+    }
+
+    progress.checkpoint(Step::Compressing, 0.5)?;
+    let mut pixel_data = atlas.into_raw();
+    let dds = compress_image(
+        width,
+        frame_height,
+        format,
+        &mut pixel_data,
+        &progress.range(0.5, 1.0),
+    )?;
+    progress.checkpoint(Step::Compressing, 1.0)?;
+    Ok(dds)
+}
+
+/// Writes every payload of the AFB file at `in_path` found by the default
+/// [`SignatureRegistry`] to `out_folder`. See [`extract_afb_with_signatures`].
+pub fn extract_afb(in_path: &Path, out_folder: &str) -> Result<usize> {
+    extract_afb_with_signatures(in_path, out_folder, &SignatureRegistry::default())
+}
+
+/// Writes every payload of the archive at `in_path` matching `signatures` to `out_folder` as
+/// `<stem>_0001.dds`, `<stem>_0002.png`, ..., with `<stem>_manifest.tsv` listing them, and
+/// returns how many were written. The file is memory-mapped and each payload written as it is
+/// found, so memory use does not grow with the size of the archive.
+pub fn extract_afb_with_signatures(
+    in_path: &Path,
+    out_folder: &str,
+    signatures: &SignatureRegistry,
+) -> Result<usize> {
+    let data = map_file(in_path)?;
     let base_name = in_path
         .file_stem()
         .and_then(|s| s.to_str())
         .unwrap_or("chunk");
-    extract_chunks(&data, out_folder, base_name, ".dds", &chunks)
+    let mut payloads = iter_payloads(&data, signatures).peekable();
+    if payloads.peek().is_none() {
+        return Err(Error::NoChunks {
+            path: in_path.to_path_buf(),
+        });
+    }
+    let count = extract_payloads(&data, out_folder, base_name, payloads)?;
+    debug!("Extracted {} payloads from {}", count, in_path.display());
+    Ok(count)
+}
+
+/// Returns every payload in the AFB file at `in_path`, numbered like the files written by
+/// `extract_afb`.
+pub fn inspect_afb(in_path: &Path) -> Result<Vec<Payload>> {
+    read_afb(in_path).map(|(_, payloads)| payloads)
+}
+
+/// Copies the AFB file at `in_path` to `out_path`, replacing the payloads at the given
+/// zero-based indices with the contents of the given files, which must be of the same type.
+/// Payloads of every type are numbered together in file order, as by `inspect_afb`, so a DDS
+/// index also counts the PNG, GNF, GTF and AFB payloads before it.
+pub fn repack_afb(in_path: &Path, out_path: &Path, replacements: &[(usize, &Path)]) -> Result<()> {
+    let (data, payloads) = read_afb(in_path)?;
+
+    let mut contents: Vec<Option<Vec<u8>>> = vec![None; payloads.len()];
+    for &(index, path) in replacements {
+        let Some(payload) = payloads.get(index) else {
+            return Err(Error::ChunkOutOfRange {
+                index,
+                max: payloads.len() - 1,
+            });
+        };
+        let replacement = fs::read(path).map_err(Error::io(path))?;
+        let signature = payload.signature;
+        if !replacement.starts_with(signature.magic) {
+            return Err(match signature.name {
+                "dds" => Error::InvalidDds {
+                    path: Some(path.to_path_buf()),
+                    offset: 0,
+                    reason: "missing DDS magic",
+                },
+                expected => Error::PayloadMismatch {
+                    path: path.to_path_buf(),
+                    expected,
+                },
+            });
+        }
+        contents[index] = Some(replacement);
+    }
+    let replacements: Vec<Option<&[u8]>> = contents.iter().map(Option::as_deref).collect();
+    let chunks: Vec<(usize, usize)> = payloads.iter().map(|p| (p.start, p.end)).collect();
+
+    let mut writer = AtomicWriter::new();
+    {
+        let mut out_file = BufWriter::new(writer.create(out_path)?);
+        replace_chunks(&data, &mut out_file, out_path, &chunks, &replacements)?;
+        out_file.flush().map_err(Error::io(out_path))?;
+    }
+    // Unmap the input first: it may be the file being replaced, and Windows refuses to
+    // rename over a mapped file.
+    drop(data);
+    writer.commit()
 }
 
+fn read_afb(in_path: &Path) -> Result<(Mmap, Vec<Payload>)> {
+    let data = map_file(in_path)?;
+    let payloads = locate_payloads(&data, &SignatureRegistry::default());
+    debug!("Found {} payloads in {}", payloads.len(), in_path.display());
+    if payloads.is_empty() {
+        return Err(Error::NoChunks {
+            path: in_path.to_path_buf(),
+        });
+    }
+    Ok((data, payloads))
+}
+
+/// Builds the st and nf AFB files of a stage. Like `convert_fx`, the FX images among the first
+/// `FX_SLOTS` entries of `fx_in_paths` fill the tiles in order and the rest are ignored. Use
+/// [`StageBuilder`] to place images in specific slots.
 pub fn convert_stage(
     bg_in_path: &Path,
     fx_in_paths: &[Option<&Path>],
     st_out_path: &Path,
     nf_out_path: &Path,
-) -> anyhow::Result<()> {
-    let bg_dds = save_dds_blob(convert_bg(bg_in_path)?)?;
-    let fx_dds = if fx_in_paths.iter().any(Option::is_some) {
-        Some(save_dds_blob(convert_fx(fx_in_paths)?)?)
-    } else {
-        None
-    };
-
-    let bg_buffer = bg_dds.buffer();
-    let fx_buffer = fx_dds.as_ref().map(|d| d.buffer()).or(Some(FX_DUMMY));
-
-    let replacements = &[Some(bg_buffer), fx_buffer];
-    replace_chunks(ST_DUMMY, st_out_path, &ST_CHUNKS, replacements)?;
-    fs::write(nf_out_path, NF_DUMMY)?;
-    Ok(())
+) -> Result<()> {
+    let mut builder = StageBuilder::new().background(bg_in_path);
+    for (slot, path) in fx_in_paths.iter().take(FX_SLOTS).flatten().enumerate() {
+        builder = builder.fx(slot, *path);
+    }
+    builder.build_to(st_out_path, nf_out_path)
 }
@@ -0,0 +1,92 @@
+use crate::img::error::{Error, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const HEADER_SIZE: usize = 128;
+const DX10_HEADER_SIZE: usize = 20;
+
+/// Header fields of a DDS file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DdsInfo {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    pub mip_count: u32,
+    pub array_size: u32,
+    /// The pixel format FourCC, e.g. `DXT1` or `DX10`, when the header has one.
+    pub four_cc: Option<String>,
+    /// The DXGI format from the DX10 extension header, when present.
+    pub dxgi_format: Option<u32>,
+}
+
+/// Reads the header of the DDS file at `path`.
+pub fn read_dds_info(path: &Path) -> Result<DdsInfo> {
+    let mut header = Vec::with_capacity(HEADER_SIZE + DX10_HEADER_SIZE);
+    File::open(path)
+        .and_then(|file| {
+            file.take((HEADER_SIZE + DX10_HEADER_SIZE) as u64)
+                .read_to_end(&mut header)
+        })
+        .map_err(Error::io(path))?;
+    dds_info(&header).map_err(|err| match err {
+        Error::InvalidDds { offset, reason, .. } => Error::InvalidDds {
+            path: Some(path.to_path_buf()),
+            offset,
+            reason,
+        },
+        err => err,
+    })
+}
+
+/// Parses the header at the start of `data`.
+pub fn dds_info(data: &[u8]) -> Result<DdsInfo> {
+    const DDPF_FOURCC: u32 = 0x4;
+    const DDSD_DEPTH: u32 = 0x800000;
+    const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+
+    let invalid = |offset, reason| Error::InvalidDds {
+        path: None,
+        offset,
+        reason,
+    };
+    if !data.starts_with(b"DDS ") {
+        return Err(invalid(0, "missing DDS magic"));
+    }
+    if data.len() < HEADER_SIZE {
+        return Err(invalid(data.len(), "truncated header"));
+    }
+    let read_u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    let flags = read_u32(8);
+    let mut info = DdsInfo {
+        width: read_u32(16),
+        height: read_u32(12),
+        depth: if flags & DDSD_DEPTH != 0 {
+            read_u32(24).max(1)
+        } else {
+            1
+        },
+        mip_count: if flags & DDSD_MIPMAPCOUNT != 0 {
+            read_u32(28).max(1)
+        } else {
+            1
+        },
+        array_size: 1,
+        four_cc: None,
+        dxgi_format: None,
+    };
+
+    if read_u32(80) & DDPF_FOURCC != 0 {
+        let four_cc = &data[84..88];
+        info.four_cc = Some(String::from_utf8_lossy(four_cc).into_owned());
+        if four_cc == b"DX10" {
+            if data.len() < HEADER_SIZE + DX10_HEADER_SIZE {
+                return Err(invalid(data.len(), "truncated DX10 header"));
+            }
+            info.dxgi_format = Some(read_u32(HEADER_SIZE));
+            info.array_size = read_u32(HEADER_SIZE + 12).max(1);
+        }
+    }
+    Ok(info)
+}
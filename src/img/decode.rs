@@ -0,0 +1,424 @@
+use crate::img::error::{Error, Result};
+use crate::img::format::{SourceFormat, detect_format};
+use crate::img::psd::{Psd, decode_psd};
+use crate::img::quantize::ToneMapping;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder, DynamicImage, Frames, ImageDecoder, ImageFormat, ImageReader, RgbaImage,
+    imageops,
+};
+use log::{debug, warn};
+use moxcms::{ColorProfile, Layout, TransformExecutor, TransformOptions};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+
+/// Which frame of an animated GIF, APNG or WebP source is converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameSelection {
+    /// The frame at this zero-based index. Still images only have frame 0.
+    Index(usize),
+    /// The frame on screen at this time from the start of the animation, or the last frame
+    /// if the animation is shorter.
+    Time(Duration),
+    /// The frame closest to the average of all frames, which skips fade-ins and blank
+    /// first frames.
+    Representative,
+}
+
+/// How source images are interpreted before they are resized, and how 16-bit and HDR
+/// images are reduced to 8 bits per channel afterwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeOptions {
+    /// Rotate and flip the image as described by its EXIF orientation tag.
+    pub orientation: bool,
+    /// Convert the pixels from an embedded ICC profile to sRGB.
+    pub color_profile: bool,
+    /// Operator applied to floating-point (HDR, OpenEXR) sources.
+    pub tone_mapping: ToneMapping,
+    /// Exposure adjustment in stops, applied to HDR sources before tone mapping.
+    pub exposure: f32,
+    /// Apply an ordered dither when quantising high-bit-depth sources to 8 bits.
+    pub dither: bool,
+    /// Frame of animated sources to convert; still images only have frame 0.
+    pub frame: FrameSelection,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeOptions {
+    pub const fn new() -> Self {
+        Self {
+            orientation: true,
+            color_profile: true,
+            tone_mapping: ToneMapping::Aces,
+            exposure: 0.0,
+            dither: true,
+            frame: FrameSelection::Index(0),
+        }
+    }
+
+    pub const fn orientation(mut self, orientation: bool) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    pub const fn color_profile(mut self, color_profile: bool) -> Self {
+        self.color_profile = color_profile;
+        self
+    }
+
+    pub const fn tone_mapping(mut self, tone_mapping: ToneMapping) -> Self {
+        self.tone_mapping = tone_mapping;
+        self
+    }
+
+    pub const fn exposure(mut self, exposure: f32) -> Self {
+        self.exposure = exposure;
+        self
+    }
+
+    pub const fn dither(mut self, dither: bool) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    pub const fn frame(mut self, frame: FrameSelection) -> Self {
+        self.frame = frame;
+        self
+    }
+}
+
+/// Decodes `in_path`, detecting the format from its contents rather than the extension.
+/// Animated sources are reduced to the frame picked by `options.frame`.
+pub(crate) fn open_image(in_path: &Path, options: &DecodeOptions) -> Result<DynamicImage> {
+    let format = match detect_format(in_path)? {
+        SourceFormat::Image(format) => format,
+        SourceFormat::Psd => {
+            check_still_frame(options.frame)?;
+            return open_psd(in_path, options);
+        }
+    };
+    let mut decoder = open_decoder(in_path, format)?;
+    let metadata = Metadata::read(&mut decoder, options, in_path)?;
+    let frame = match options.frame {
+        FrameSelection::Index(0) => None,
+        selection => select_frame(in_path, format, selection)?,
+    };
+    let mut img = match frame {
+        Some(frame) => DynamicImage::ImageRgba8(frame),
+        None => {
+            check_still_frame(options.frame)?;
+            DynamicImage::from_decoder(decoder).map_err(Error::decode(in_path))?
+        }
+    };
+    debug!(
+        "Decoded {} as {:?} ({}x{}, {:?})",
+        in_path.display(),
+        format,
+        img.width(),
+        img.height(),
+        img.color()
+    );
+
+    metadata.apply(&mut img, in_path);
+    Ok(img)
+}
+
+/// Frames of an image, decoded one at a time as the iterator advances.
+pub(crate) type FrameIter = Box<dyn Iterator<Item = Result<DynamicImage>>>;
+
+/// Opens the frames of `in_path` for decoding. Still images yield a single frame; callers stop
+/// iterating once they have as many frames as they can use.
+pub(crate) fn open_frames(in_path: &Path, options: &DecodeOptions) -> Result<FrameIter> {
+    let format = match detect_format(in_path)? {
+        SourceFormat::Image(format) => format,
+        SourceFormat::Psd => return Ok(Box::new(std::iter::once(open_psd(in_path, options)))),
+    };
+    let mut decoder = open_decoder(in_path, format)?;
+    let metadata = Metadata::read(&mut decoder, options, in_path)?;
+    // Animations without any frames fall back to the decoder like still images.
+    let Some(frames) = read_frames(in_path, format)?
+        .map(Iterator::peekable)
+        .and_then(|mut frames| frames.peek().is_some().then_some(frames))
+    else {
+        let mut img = DynamicImage::from_decoder(decoder).map_err(Error::decode(in_path))?;
+        metadata.apply(&mut img, in_path);
+        return Ok(Box::new(std::iter::once(Ok(img))));
+    };
+
+    debug!("Decoding animation frames from {}", in_path.display());
+    let in_path = in_path.to_path_buf();
+    Ok(Box::new(frames.map(move |frame| {
+        let frame = frame.map_err(Error::decode(&in_path))?;
+        let mut img = DynamicImage::ImageRgba8(frame.into_buffer());
+        metadata.apply(&mut img, &in_path);
+        Ok(img)
+    })))
+}
+
+fn open_decoder(in_path: &Path, format: ImageFormat) -> Result<impl ImageDecoder> {
+    let mut reader = ImageReader::open(in_path).map_err(Error::io(in_path))?;
+    reader.set_format(format);
+    reader.into_decoder().map_err(Error::decode(in_path))
+}
+
+fn open_psd(in_path: &Path, options: &DecodeOptions) -> Result<DynamicImage> {
+    let data = fs::read(in_path).map_err(Error::io(in_path))?;
+    let Psd {
+        mut image,
+        icc_profile,
+    } = decode_psd(&data, in_path)?;
+    debug!(
+        "Decoded the composite of {} ({}x{}, {:?})",
+        in_path.display(),
+        image.width(),
+        image.height(),
+        image.color()
+    );
+
+    let metadata = Metadata {
+        orientation: Orientation::NoTransforms,
+        icc_profile: icc_profile.filter(|_| options.color_profile),
+    };
+    metadata.apply(&mut image, in_path);
+    Ok(image)
+}
+
+/// Fails for frame selections other than the first frame, which is all a still image has.
+fn check_still_frame(frame: FrameSelection) -> Result<()> {
+    match frame {
+        FrameSelection::Index(index @ 1..) => Err(Error::FrameOutOfRange { index, max: 0 }),
+        _ => Ok(()),
+    }
+}
+
+/// Orientation and ICC profile of a source, applied after its pixels are decoded.
+struct Metadata {
+    orientation: Orientation,
+    icc_profile: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    fn read(
+        decoder: &mut impl ImageDecoder,
+        options: &DecodeOptions,
+        in_path: &Path,
+    ) -> Result<Self> {
+        let orientation = if options.orientation {
+            decoder.orientation().map_err(Error::decode(in_path))?
+        } else {
+            Orientation::NoTransforms
+        };
+        let icc_profile = if options.color_profile {
+            decoder.icc_profile().map_err(Error::decode(in_path))?
+        } else {
+            None
+        };
+        Ok(Self {
+            orientation,
+            icc_profile,
+        })
+    }
+
+    fn apply(&self, img: &mut DynamicImage, in_path: &Path) {
+        if let Some(icc_profile) = &self.icc_profile {
+            convert_to_srgb(img, icc_profile, in_path);
+        }
+        if self.orientation != Orientation::NoTransforms {
+            debug!("Applying EXIF orientation {:?}", self.orientation);
+            img.apply_orientation(self.orientation);
+        }
+    }
+}
+
+/// Starts decoding the frames of an animated GIF, APNG or WebP file, or returns `None` when
+/// `in_path` is not animated. Frames are decoded one at a time as the iterator advances.
+fn read_frames(in_path: &Path, format: ImageFormat) -> Result<Option<Frames<'static>>> {
+    let open = || {
+        File::open(in_path)
+            .map(BufReader::new)
+            .map_err(Error::io(in_path))
+    };
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(open()?)
+            .map_err(Error::decode(in_path))?
+            .into_frames(),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(open()?).map_err(Error::decode(in_path))?;
+            if !decoder.is_apng().map_err(Error::decode(in_path))? {
+                return Ok(None);
+            }
+            decoder
+                .apng()
+                .map_err(Error::decode(in_path))?
+                .into_frames()
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(open()?).map_err(Error::decode(in_path))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(frames))
+}
+
+/// Decodes the frame of an animated `in_path` picked by `selection`, or returns `None` when
+/// it is not animated. Frames past the selected one are never decoded, and only one frame is
+/// held at a time.
+fn select_frame(
+    in_path: &Path,
+    format: ImageFormat,
+    selection: FrameSelection,
+) -> Result<Option<RgbaImage>> {
+    let selection = match selection {
+        FrameSelection::Representative => match representative_frame(in_path, format)? {
+            Some(index) => FrameSelection::Index(index),
+            None => return Ok(None),
+        },
+        selection => selection,
+    };
+    let Some(frames) = read_frames(in_path, format)? else {
+        return Ok(None);
+    };
+
+    let mut elapsed = Duration::ZERO;
+    let mut last = None;
+    for (index, frame) in frames.enumerate() {
+        let frame = frame.map_err(Error::decode(in_path))?;
+        let selected = match selection {
+            FrameSelection::Index(target) => index == target,
+            FrameSelection::Time(time) => {
+                elapsed += Duration::from(frame.delay());
+                elapsed > time
+            }
+            FrameSelection::Representative => false,
+        };
+        if selected {
+            debug!("Selected frame {}", index);
+            return Ok(Some(frame.into_buffer()));
+        }
+        last = Some((index, frame));
+    }
+
+    match (selection, last) {
+        (_, None) => Ok(None),
+        (FrameSelection::Index(index), Some((max, _))) => {
+            Err(Error::FrameOutOfRange { index, max })
+        }
+        (_, Some((index, frame))) => {
+            debug!("Selected the last frame, {}", index);
+            Ok(Some(frame.into_buffer()))
+        }
+    }
+}
+
+/// Index of the frame whose 16x16 thumbnail is closest to the mean of all thumbnails, or
+/// `None` when `in_path` is not animated. Only the thumbnails are kept while decoding.
+fn representative_frame(in_path: &Path, format: ImageFormat) -> Result<Option<usize>> {
+    const SIZE: u32 = 16;
+    let Some(frames) = read_frames(in_path, format)? else {
+        return Ok(None);
+    };
+    let thumbnails = frames
+        .map(|frame| {
+            let frame = frame.map_err(Error::decode(in_path))?;
+            let thumbnail = imageops::thumbnail(frame.buffer(), SIZE, SIZE);
+            Ok(thumbnail.into_raw().into_iter().map(f32::from).collect())
+        })
+        .collect::<Result<Vec<Vec<f32>>>>()?;
+
+    let mut mean = vec![0.0; (SIZE * SIZE * 4) as usize];
+    for thumbnail in &thumbnails {
+        for (m, v) in mean.iter_mut().zip(thumbnail) {
+            *m += v / thumbnails.len() as f32;
+        }
+    }
+    Ok(thumbnails
+        .iter()
+        .map(|thumbnail| {
+            let distance: f32 = thumbnail
+                .iter()
+                .zip(&mean)
+                .map(|(v, m)| (v - m) * (v - m))
+                .sum();
+            distance
+        })
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index))
+}
+
+/// Converts `img` in place from `icc_profile` to sRGB. Profiles that can't be parsed or
+/// applied are logged and the pixels are left as they are, like browsers do.
+fn convert_to_srgb(img: &mut DynamicImage, icc_profile: &[u8], in_path: &Path) {
+    let source = match ColorProfile::new_from_slice(icc_profile) {
+        Ok(profile) => profile,
+        Err(err) => {
+            warn!(
+                "Ignoring invalid ICC profile in {}: {}",
+                in_path.display(),
+                err
+            );
+            return;
+        }
+    };
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+
+    let color = img.color();
+    let result = match img {
+        DynamicImage::ImageRgb8(buf) => source
+            .create_transform_8bit(Layout::Rgb, &srgb, Layout::Rgb, options)
+            .and_then(|t| transform_in_place(t.as_ref(), buf)),
+        DynamicImage::ImageRgba8(buf) => source
+            .create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgba, options)
+            .and_then(|t| transform_in_place(t.as_ref(), buf)),
+        DynamicImage::ImageRgb16(buf) => source
+            .create_transform_16bit(Layout::Rgb, &srgb, Layout::Rgb, options)
+            .and_then(|t| transform_in_place(t.as_ref(), buf)),
+        DynamicImage::ImageRgba16(buf) => source
+            .create_transform_16bit(Layout::Rgba, &srgb, Layout::Rgba, options)
+            .and_then(|t| transform_in_place(t.as_ref(), buf)),
+        DynamicImage::ImageRgb32F(buf) => source
+            .create_transform_f32(Layout::Rgb, &srgb, Layout::Rgb, options)
+            .and_then(|t| transform_in_place(t.as_ref(), buf)),
+        DynamicImage::ImageRgba32F(buf) => source
+            .create_transform_f32(Layout::Rgba, &srgb, Layout::Rgba, options)
+            .and_then(|t| transform_in_place(t.as_ref(), buf)),
+        _ => {
+            debug!("Skipping ICC conversion of {:?} pixels", color);
+            return;
+        }
+    };
+    match result {
+        Ok(()) => debug!(
+            "Converted {} from its ICC profile to sRGB",
+            in_path.display()
+        ),
+        Err(err) => warn!(
+            "Ignoring ICC profile in {} that can't be applied: {}",
+            in_path.display(),
+            err
+        ),
+    }
+}
+
+fn transform_in_place<V: Copy + Default>(
+    transform: &(dyn TransformExecutor<V> + Send + Sync),
+    pixels: &mut [V],
+) -> std::result::Result<(), moxcms::CmsError> {
+    let source = pixels.to_vec();
+    transform.transform(&source, pixels)
+}
@@ -0,0 +1,133 @@
+use crate::error::ErrorCode;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the `img` API. `code` maps each one onto the stable `ErrorCode`
+/// reported through the C exports.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error on {}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to decode {}", .path.display())]
+    Decode {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+    #[error("Invalid dimensions {width}x{height}: width and height must be greater than 0")]
+    InvalidDimensions { width: u32, height: u32 },
+    #[error("No background image set for the stage")]
+    MissingBackground,
+    #[error("FX slot {slot} is out of range (0-{max})")]
+    FxSlotOutOfRange { slot: usize, max: usize },
+    #[error("Chunk {index} is out of range (0-{max})")]
+    ChunkOutOfRange { index: usize, max: usize },
+    #[error("Frame {index} is out of range (0-{max})")]
+    FrameOutOfRange { index: usize, max: usize },
+    #[error(
+        "Replacements length ({replacements}) must be at least equal to chunks length ({chunks})"
+    )]
+    ReplacementCount { replacements: usize, chunks: usize },
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Unknown preset: {0}")]
+    UnknownPreset(String),
+    #[error("Invalid preset on line {line}: {message}")]
+    InvalidPreset { line: usize, message: String },
+    #[error("{0}")]
+    Compression(String),
+    #[error("No embedded files found in {}", .path.display())]
+    NoChunks { path: PathBuf },
+    #[error("Invalid DDS data at byte {offset}{}: {reason}", path_suffix(.path))]
+    InvalidDds {
+        path: Option<PathBuf>,
+        offset: usize,
+        reason: &'static str,
+    },
+    #[error("{} is not a {expected} file", .path.display())]
+    PayloadMismatch {
+        path: PathBuf,
+        expected: &'static str,
+    },
+    #[error("Invalid PSD {} at byte {offset}: {reason}", .path.display())]
+    InvalidPsd {
+        path: PathBuf,
+        offset: usize,
+        reason: &'static str,
+    },
+    #[error("Invalid Music.xml {} at byte {offset}: {reason}", .path.display())]
+    InvalidMusicXml {
+        path: PathBuf,
+        offset: usize,
+        reason: &'static str,
+    },
+    #[error("Operation cancelled")]
+    Cancelled,
+    #[error("Panic: {0}")]
+    Panic(String),
+    #[error("Preset registry is poisoned")]
+    Poisoned,
+    #[error("Failed to start the worker pool")]
+    ThreadPool(#[source] rayon::ThreadPoolBuildError),
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Io { .. } => ErrorCode::Io,
+            Error::Decode { source, .. } => match source {
+                image::ImageError::IoError(_) => ErrorCode::Io,
+                image::ImageError::Parameter(_) | image::ImageError::Limits(_) => {
+                    ErrorCode::InvalidArgument
+                }
+                _ => ErrorCode::Decode,
+            },
+            Error::InvalidPsd { .. } => ErrorCode::Decode,
+            Error::InvalidDimensions { .. }
+            | Error::MissingBackground
+            | Error::FxSlotOutOfRange { .. }
+            | Error::ChunkOutOfRange { .. }
+            | Error::FrameOutOfRange { .. }
+            | Error::ReplacementCount { .. }
+            | Error::UnsupportedFormat(_)
+            | Error::UnknownPreset(_)
+            | Error::InvalidPreset { .. } => ErrorCode::InvalidArgument,
+            Error::Compression(_) => ErrorCode::Compression,
+            Error::NoChunks { .. }
+            | Error::InvalidDds { .. }
+            | Error::PayloadMismatch { .. }
+            | Error::InvalidMusicXml { .. } => ErrorCode::ContainerFormat,
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::Panic(_) => ErrorCode::Panic,
+            Error::Poisoned | Error::ThreadPool(_) => ErrorCode::Failure,
+        }
+    }
+
+    /// Wraps an I/O error with the path it occurred on, for use with `map_err`.
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub(crate) fn decode(path: &Path) -> impl FnOnce(image::ImageError) -> Error + '_ {
+        move |source| Error::Decode {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+fn path_suffix(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => format!(" in {}", path.display()),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,84 @@
+use crate::img::error::{Error, Result};
+use image::ImageFormat;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const TGA_HEADER_SIZE: usize = 18;
+
+/// Format of a source image, detected from its contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SourceFormat {
+    /// A format decoded by the `image` crate.
+    Image(ImageFormat),
+    /// A Photoshop PSD or PSB document, read from its flattened composite.
+    Psd,
+}
+
+/// Detects the format of `in_path` from its first bytes. TGA has no signature, so its header is
+/// checked for consistency with the file size instead.
+pub(crate) fn detect_format(in_path: &Path) -> Result<SourceFormat> {
+    let mut file = File::open(in_path).map_err(Error::io(in_path))?;
+    let file_len = file.metadata().map_err(Error::io(in_path))?.len();
+    let mut header = Vec::with_capacity(32);
+    file.by_ref()
+        .take(32)
+        .read_to_end(&mut header)
+        .map_err(Error::io(in_path))?;
+
+    if header.starts_with(b"8BPS") && matches!(header.get(4..6), Some([0, 1 | 2])) {
+        return Ok(SourceFormat::Psd);
+    }
+    match image::guess_format(&header) {
+        Ok(format) => Ok(SourceFormat::Image(format)),
+        Err(_) if is_tga_header(&header, file_len) => Ok(SourceFormat::Image(ImageFormat::Tga)),
+        Err(err) => Err(Error::decode(in_path)(err)),
+    }
+}
+
+fn is_tga_header(header: &[u8], file_len: u64) -> bool {
+    let Some(header) = header.get(..TGA_HEADER_SIZE) else {
+        return false;
+    };
+    let read_u16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]) as u64;
+    let id_length = header[0] as u64;
+    let color_map_type = header[1];
+    let image_type = header[2];
+    let color_map_length = read_u16(5);
+    let color_map_depth = header[7];
+    let (width, height) = (read_u16(12), read_u16(14));
+    let pixel_depth = header[16];
+    let descriptor = header[17];
+
+    let color_mapped = matches!(image_type, 1 | 9);
+    let pixel_depth_ok = match image_type {
+        1 | 9 => matches!(pixel_depth, 8 | 16),
+        2 | 10 => matches!(pixel_depth, 15 | 16 | 24 | 32),
+        3 | 11 => matches!(pixel_depth, 8 | 16),
+        _ => return false,
+    };
+    let color_map_size = match color_map_type {
+        0 if !color_mapped => 0,
+        1 if color_map_length > 0 && matches!(color_map_depth, 15 | 16 | 24 | 32) => {
+            color_map_length * (color_map_depth as u64).div_ceil(8)
+        }
+        _ => return false,
+    };
+    // The low nibble counts alpha bits; the top two bits are the unused interleave flag.
+    if !pixel_depth_ok
+        || width == 0
+        || height == 0
+        || (descriptor & 0x0f) > pixel_depth
+        || descriptor & 0xc0 != 0
+    {
+        return false;
+    }
+
+    let data_offset = TGA_HEADER_SIZE as u64 + id_length + color_map_size;
+    let pixel_size = width * height * (pixel_depth as u64).div_ceil(8);
+    if image_type < 9 {
+        file_len >= data_offset + pixel_size
+    } else {
+        file_len > data_offset
+    }
+}
@@ -1,102 +1,419 @@
-use anyhow::Result;
+use crate::img::atomic::AtomicWriter;
+use crate::img::error::{Error, Result};
+use log::trace;
+use memchr::memmem::Finder;
+use memmap2::Mmap;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
-pub fn locate_dds_chunks(input: &[u8]) -> Vec<(usize, usize)> {
-    const DDS_HEADER: &[u8] = &[0x44, 0x44, 0x53, 0x20]; // "DDS "
-    const DDS_STOP_SIGN: &[u8] = &[0x50, 0x4F, 0x46, 0x30]; // "POF0"
+/// Reads a payload's size from the bytes starting at its signature.
+pub type LengthFn = fn(&[u8]) -> Option<usize>;
 
-    locate_chunks(input, DDS_HEADER, DDS_STOP_SIGN)
+/// A kind of file embedded in archives, recognised by the bytes it starts with.
+#[derive(Debug, Clone, Copy)]
+pub struct Signature {
+    /// Short name used in manifests, e.g. "png".
+    pub name: &'static str,
+    pub magic: &'static [u8],
+    /// Extension of the extracted files, including the dot.
+    pub extension: &'static str,
+    /// Reads the payload size from its header, for formats that record it. A `None` result
+    /// rejects the match as a coincidence in other data.
+    pub length: Option<LengthFn>,
+    /// Ends payloads without a length field; they also end at the next payload found.
+    pub terminator: Option<&'static [u8]>,
+    /// Containers are extracted whole, except the one the input itself starts with, whose
+    /// contents are searched instead.
+    pub container: bool,
 }
 
-pub fn locate_chunks(input: &[u8], header: &[u8], stop_sign: &[u8]) -> Vec<(usize, usize)> {
-    let mut chunks = Vec::new();
-    let mut current_pos = 0;
+/// A payload found by `iter_payloads`, spanning `start..end` of the input.
+#[derive(Debug, Clone, Copy)]
+pub struct Payload {
+    pub signature: Signature,
+    pub start: usize,
+    pub end: usize,
+}
 
-    while let Some(start) = find_chunks(input, header, current_pos) {
-        let stop_pos = find_chunks(input, stop_sign, start + header.len());
-        let next_header = find_chunks(input, header, start + header.len());
+/// The signatures searched for when extracting, starting with the formats found in game
+/// archives: DDS, PNG, GNF (PS4) and GTF (PS3) textures and nested AFB (FILS) containers.
+pub struct SignatureRegistry {
+    signatures: Vec<Signature>,
+}
 
-        match (stop_pos, next_header) {
-            (Some(stop), Some(next)) => {
-                let end = std::cmp::min(stop, next);
-                chunks.push((start, end));
-                current_pos = end;
-            }
-            (Some(stop), None) => {
-                chunks.push((start, stop));
-                current_pos = stop;
-            }
-            (None, Some(next)) => {
-                chunks.push((start, next));
-                current_pos = next;
+impl Default for SignatureRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(Signature {
+            name: "dds",
+            magic: b"DDS ",
+            extension: ".dds",
+            length: None,
+            terminator: Some(b"POF0"),
+            container: false,
+        });
+        registry.register(Signature {
+            name: "png",
+            magic: b"\x89PNG\r\n\x1a\n",
+            extension: ".png",
+            length: Some(png_length),
+            terminator: None,
+            container: false,
+        });
+        registry.register(Signature {
+            name: "gnf",
+            magic: b"GNF ",
+            extension: ".gnf",
+            length: Some(gnf_length),
+            terminator: None,
+            container: false,
+        });
+        registry.register(Signature {
+            name: "gtf",
+            magic: GTF_MAGIC,
+            extension: ".gtf",
+            length: Some(gtf_length),
+            terminator: None,
+            container: false,
+        });
+        registry.register(Signature {
+            name: "afb",
+            magic: b"FILS",
+            extension: ".afb",
+            length: Some(fils_length),
+            terminator: None,
+            container: true,
+        });
+        registry
+    }
+}
+
+impl SignatureRegistry {
+    pub fn empty() -> Self {
+        Self {
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Adds `signature`. When several match at the same offset, the first registered wins.
+    pub fn register(&mut self, signature: Signature) {
+        self.signatures.push(signature);
+    }
+
+    pub fn signatures(&self) -> &[Signature] {
+        &self.signatures
+    }
+}
+
+/// Returns every payload in `input` matching one of the `signatures`.
+pub fn locate_payloads(input: &[u8], signatures: &SignatureRegistry) -> Vec<Payload> {
+    iter_payloads(input, signatures).collect()
+}
+
+/// Like `locate_payloads`, but finds each payload only when it is asked for.
+pub fn iter_payloads<'a>(input: &'a [u8], signatures: &'a SignatureRegistry) -> PayloadIter<'a> {
+    let signatures = &signatures.signatures;
+    PayloadIter {
+        input,
+        signatures,
+        magics: signatures
+            .iter()
+            .map(|s| Occurrences::new(s.magic))
+            .collect(),
+        terminators: signatures
+            .iter()
+            .map(|s| Occurrences::new(s.terminator.unwrap_or_default()))
+            .collect(),
+        pos: 0,
+    }
+}
+
+/// The payloads of an input. Each pattern is searched for in a single forward pass, so the cost
+/// is linear in the size of the input however many payloads it holds.
+pub struct PayloadIter<'a> {
+    input: &'a [u8],
+    signatures: &'a [Signature],
+    magics: Vec<Occurrences<'a>>,
+    terminators: Vec<Occurrences<'a>>,
+    pos: usize,
+}
+
+impl PayloadIter<'_> {
+    /// The first signature match at or after `pos`, as `(offset, signature index)`.
+    fn next_match(&mut self, pos: usize) -> Option<(usize, usize)> {
+        let input = self.input;
+        self.magics
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, magic)| Some((magic.next_from(input, pos)?, i)))
+            .min()
+    }
+
+    /// End of the payload of `signature` at `start` read from its length field, or `None`
+    /// when the header doesn't hold up.
+    fn sized_end(&self, start: usize, signature: &Signature, length: LengthFn) -> Option<usize> {
+        let len = length(&self.input[start..])?;
+        (len >= signature.magic.len() && len <= self.input.len() - start).then_some(start + len)
+    }
+
+    /// End of the payload of the unsized signature at `index` whose body starts at `body`: its
+    /// terminator, or the next header of the same kind or of one whose length field holds up,
+    /// whichever comes first. Weak magics inside the payload don't cut it short.
+    fn unsized_end(&mut self, index: usize, body: usize) -> usize {
+        let input = self.input;
+        let terminator = self.terminators[index]
+            .next_from(input, body)
+            .unwrap_or(input.len());
+        let mut pos = body;
+        while let Some((next, i)) = self.next_match(pos).filter(|&(next, _)| next < terminator) {
+            let signature = &self.signatures[i];
+            let is_header = match signature.length {
+                Some(length) if i != index => self.sized_end(next, signature, length).is_some(),
+                _ => true,
+            };
+            if is_header {
+                return next;
             }
-            (None, None) => {
-                chunks.push((start, input.len()));
-                break;
+            trace!("Ignoring {} signature at {:#x}", signature.name, next);
+            pos = next + 1;
+        }
+        terminator
+    }
+}
+
+impl Iterator for PayloadIter<'_> {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        loop {
+            let (start, index) = self.next_match(self.pos)?;
+            let signature = self.signatures[index];
+            let body = start + signature.magic.len();
+            if signature.container && start == 0 {
+                self.pos = body;
+                continue;
             }
+
+            let end = match signature.length {
+                Some(length) => match self.sized_end(start, &signature, length) {
+                    Some(end) => end,
+                    None => {
+                        trace!("Ignoring {} signature at {:#x}", signature.name, start);
+                        self.pos = start + 1;
+                        continue;
+                    }
+                },
+                None => self.unsized_end(index, body),
+            };
+            self.pos = end;
+            trace!(
+                "{} at {:#x}..{:#x} ({} bytes)",
+                signature.name,
+                start,
+                end,
+                end - start
+            );
+            return Some(Payload {
+                signature,
+                start,
+                end,
+            });
         }
     }
+}
+
+/// Version field of GTF files written by the current PS3 SDK, used as their signature.
+const GTF_MAGIC: &[u8] = &[0x02, 0x02, 0x00, 0xff];
 
-    chunks
+fn read_u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
 }
 
-pub fn extract_chunks(
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Walks the PNG chunks up to and including IEND.
+fn png_length(data: &[u8]) -> Option<usize> {
+    let mut pos = 8;
+    loop {
+        let len = read_u32_be(data, pos)? as usize;
+        let kind = data.get(pos + 4..pos + 8)?;
+        // Length, type, data and CRC.
+        pos = pos.checked_add(len)?.checked_add(12)?;
+        if kind == b"IEND" {
+            return Some(pos);
+        }
+    }
+}
+
+/// GNF headers record the size of the whole file, textures included.
+fn gnf_length(data: &[u8]) -> Option<usize> {
+    let contents_size = read_u32_le(data, 4)? as usize;
+    let texture_count = *data.get(9)?;
+    let stream_size = read_u32_le(data, 12)? as usize;
+    (texture_count > 0 && stream_size >= 8 + contents_size).then_some(stream_size)
+}
+
+/// GTF files end with the last of the textures listed after the header.
+fn gtf_length(data: &[u8]) -> Option<usize> {
+    const HEADER_SIZE: usize = 12;
+    const ATTRIBUTE_SIZE: usize = 36;
+
+    let texture_count = read_u32_be(data, 8)? as usize;
+    if !(1..=256).contains(&texture_count) {
+        return None;
+    }
+    let attributes_end = HEADER_SIZE + texture_count * ATTRIBUTE_SIZE;
+    (0..texture_count).try_fold(attributes_end, |end, i| {
+        let attribute = HEADER_SIZE + i * ATTRIBUTE_SIZE;
+        let offset = read_u32_be(data, attribute + 4)? as usize;
+        let size = read_u32_be(data, attribute + 8)? as usize;
+        let texture_end = offset.checked_add(size)?;
+        (offset >= attributes_end).then_some(end.max(texture_end))
+    })
+}
+
+/// AFB files are sequences of chunks, each with its data size at offset 4 and header size at
+/// offset 8, ending with an EOFC chunk.
+fn fils_length(data: &[u8]) -> Option<usize> {
+    const MAX_CHUNKS: usize = 1024;
+
+    let mut pos = 0;
+    for _ in 0..MAX_CHUNKS {
+        let kind = data.get(pos..pos + 4)?;
+        let size = read_u32_le(data, pos + 4)? as usize;
+        let header_size = read_u32_le(data, pos + 8)? as usize;
+        if header_size < 12 {
+            return None;
+        }
+        pos = pos.checked_add(header_size)?.checked_add(size)?;
+        if kind == b"EOFC" {
+            return Some(pos);
+        }
+    }
+    None
+}
+
+/// Memory-maps the file at `path` for read-only access.
+pub fn map_file(path: &Path) -> Result<Mmap> {
+    let file = File::open(path).map_err(Error::io(path))?;
+    // SAFETY: the map is only read. Inputs are not expected to be modified while they are
+    // being converted; if one is, the chunks found may not match what is later read.
+    let map = unsafe { Mmap::map(&file) }.map_err(Error::io(path))?;
+    // Chunks are read front to back, so pages behind the scan can be dropped early.
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Ok(map)
+}
+
+/// Writes each payload to `out_folder` as `<base_name>_0001<extension>`, ... as soon as it is
+/// found, followed by `<base_name>_manifest.tsv` listing the file, type, offset and size of
+/// each. Returns how many payloads were written; the files appear together once all are.
+pub fn extract_payloads(
     input: &[u8],
     out_folder: &str,
     base_name: &str,
-    extension: &str,
-    chunks: &[(usize, usize)],
-) -> Result<()> {
-    for (i, &(start, end)) in chunks.iter().enumerate() {
-        let file_name = format!("{}_{:04}{}", base_name, i + 1, extension);
-        let path = Path::new(out_folder).join(file_name);
-        let mut file = File::create(path)?;
-        file.write_all(&input[start..end])?;
+    payloads: impl IntoIterator<Item = Payload>,
+) -> Result<usize> {
+    let mut writer = AtomicWriter::new();
+    let mut manifest = String::from("file\ttype\toffset\tsize\n");
+    let mut count = 0;
+    for payload in payloads {
+        count += 1;
+        let file_name = format!("{}_{:04}{}", base_name, count, payload.signature.extension);
+        let path = Path::new(out_folder).join(&file_name);
+        writer.write(&path, &input[payload.start..payload.end])?;
+        manifest.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            file_name,
+            payload.signature.name,
+            payload.start,
+            payload.end - payload.start
+        ));
     }
-    Ok(())
+    let manifest_path = Path::new(out_folder).join(format!("{}_manifest.tsv", base_name));
+    writer.write(&manifest_path, manifest.as_bytes())?;
+    writer.commit()?;
+    Ok(count)
 }
 
+/// Streams `input` into `out_file` with each chunk swapped for its replacement, if any.
+/// `out_path` names the output in I/O errors.
 pub fn replace_chunks(
     input: &[u8],
+    out_file: &mut impl Write,
     out_path: &Path,
     chunks: &[(usize, usize)],
     replacements: &[Option<&[u8]>],
 ) -> Result<()> {
     if replacements.len() < chunks.len() {
-        anyhow::bail!(
-            "Replacements length ({}) must be at least equal to chunks length ({})",
-            replacements.len(),
-            chunks.len()
-        );
+        return Err(Error::ReplacementCount {
+            replacements: replacements.len(),
+            chunks: chunks.len(),
+        });
     }
 
-    let mut out_file = File::create(out_path)?;
     let mut cursor = 0;
 
     for (i, &(s, e)) in chunks.iter().enumerate() {
-        out_file.write_all(&input[cursor..s])?;
+        out_file
+            .write_all(&input[cursor..s])
+            .map_err(Error::io(out_path))?;
 
         match &replacements[i] {
-            Some(data) => out_file.write_all(data)?,
-            None => out_file.write_all(&input[s..e])?,
+            Some(data) => out_file.write_all(data).map_err(Error::io(out_path))?,
+            None => out_file
+                .write_all(&input[s..e])
+                .map_err(Error::io(out_path))?,
         }
 
         cursor = e;
     }
 
-    out_file.write_all(&input[cursor..])?;
+    out_file
+        .write_all(&input[cursor..])
+        .map_err(Error::io(out_path))?;
 
     Ok(())
 }
 
-fn find_chunks(haystack: &[u8], needle: &[u8], start: usize) -> Option<usize> {
-    if start >= haystack.len() || needle.is_empty() {
-        return None;
+/// Successive occurrences of one pattern. Positions passed to `next_from` never decrease, so
+/// the bytes before the last match found are never scanned again.
+struct Occurrences<'n> {
+    finder: Finder<'n>,
+    /// The position last searched from and the match found there, if any.
+    last: Option<(usize, Option<usize>)>,
+}
+
+impl<'n> Occurrences<'n> {
+    fn new(needle: &'n [u8]) -> Self {
+        Self {
+            finder: Finder::new(needle),
+            last: None,
+        }
+    }
+
+    /// Returns the first occurrence at or after `pos`.
+    fn next_from(&mut self, haystack: &[u8], pos: usize) -> Option<usize> {
+        if self.finder.needle().is_empty() {
+            return None;
+        }
+        match self.last {
+            Some((from, found)) if from <= pos && found.is_none_or(|found| found >= pos) => found,
+            _ => {
+                let found = haystack
+                    .get(pos..)
+                    .and_then(|rest| self.finder.find(rest))
+                    .map(|offset| pos + offset);
+                self.last = Some((pos, found));
+                found
+            }
+        }
     }
-    haystack[start..]
-        .windows(needle.len())
-        .position(|window| window == needle)
-        .map(|pos| pos + start)
 }
@@ -1,8 +1,50 @@
+//! Image conversion API used by the C exports and available to Rust consumers directly.
+//!
+//! Conversions return `ScratchImage`s that can be written with [`save_dds_file`]; the
+//! stage and package builders write their outputs atomically.
+
 mod assets;
+mod atomic;
+mod batch;
+mod cache;
 mod convert;
+mod dds;
+mod decode;
+mod error;
+mod format;
 mod locate;
+mod package;
+mod preset;
+mod progress;
+mod psd;
+mod quantize;
+mod stage;
 mod tests;
 mod utils;
 
+pub use self::batch::{Job, run_batch, run_batch_with_cancel};
+pub use self::cache::{ConversionCache, PruneStats, prune_cache, set_cache_dir};
+pub use self::dds::{DdsInfo, dds_info, read_dds_info};
+pub use self::decode::{DecodeOptions, FrameSelection};
+pub use self::error::{Error, Result};
+pub use self::locate::{LengthFn, Payload, Signature, SignatureRegistry};
+pub use self::package::{
+    StageInfo, build_jacket_package, build_jacket_package_with_options, build_stage_package,
+    jacket_file_name, music_dir_name, patch_music_xml,
+};
+pub use self::preset::{
+    AlphaMode, FitMode, Preset, PresetRegistry, convert_preset, convert_preset_file,
+    convert_preset_with_progress, convert_with_preset, get_preset, load_presets, parse_format,
+};
+pub use self::progress::{CancelToken, Progress, Step};
+pub use self::quantize::ToneMapping;
+pub use self::stage::{FX_SLOTS, StageBuilder};
+#[cfg(feature = "ffi")]
+pub(crate) use self::utils::panic_message;
 pub use self::utils::{is_valid_image, save_dds_blob, save_dds_file};
-pub use convert::{convert_bg, convert_dds, convert_fx, convert_jk, convert_stage, extract_afb};
+pub use convert::{
+    convert_atlas, convert_bg, convert_dds, convert_dds_with_options, convert_dds_with_progress,
+    convert_fx, convert_fx_animation, convert_fx_with_options, convert_fx_with_progress,
+    convert_jk, convert_jk_file, convert_jk_with_options, convert_jk_with_progress, convert_stage,
+    extract_afb, inspect_afb, repack_afb,
+};
@@ -0,0 +1,231 @@
+use crate::img::atomic::{AtomicWriter, write_atomic};
+use crate::img::convert::jacket_dds;
+use crate::img::decode::DecodeOptions;
+use crate::img::error::{Error, Result};
+use crate::img::progress::Progress;
+use crate::img::stage::StageBuilder;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub struct StageInfo {
+    pub id: u32,
+    pub name: String,
+    pub notes_field_line_id: i32,
+    pub notes_field_line_name: String,
+}
+
+impl StageInfo {
+    pub fn new(id: u32, name: impl Into<String>) -> Self {
+        Self {
+            id,
+            name: name.into(),
+            notes_field_line_id: 8,
+            notes_field_line_name: "Orange".to_string(),
+        }
+    }
+
+    pub fn notes_field_line(mut self, id: i32, name: impl Into<String>) -> Self {
+        self.notes_field_line_id = id;
+        self.notes_field_line_name = name.into();
+        self
+    }
+
+    pub fn dir_name(&self) -> String {
+        format!("stage{:06}", self.id)
+    }
+
+    pub fn st_file_name(&self) -> String {
+        format!("st_{:06}.afb", self.id)
+    }
+
+    pub fn nf_file_name(&self) -> String {
+        format!("nf_{:06}.afb", self.id)
+    }
+
+    pub fn to_xml(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<StageData xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xmlns:xsd="http://www.w3.org/2001/XMLSchema">
+  <dataName>{dir_name}</dataName>
+  <releaseTagName>
+    <id>-1</id>
+    <str>Invalid</str>
+    <data />
+  </releaseTagName>
+  <netOpenName>
+    <id>-1</id>
+    <str>Invalid</str>
+    <data />
+  </netOpenName>
+  <disableFlag>false</disableFlag>
+  <name>
+    <id>{id}</id>
+    <str>{name}</str>
+    <data />
+  </name>
+  <notesFieldLine>
+    <id>{line_id}</id>
+    <str>{line_name}</str>
+    <data />
+  </notesFieldLine>
+  <notesFieldFile>
+    <path>{nf_file}</path>
+  </notesFieldFile>
+  <baseFile>
+    <path>{st_file}</path>
+  </baseFile>
+  <objectFile>
+    <path />
+  </objectFile>
+</StageData>
+"#,
+            dir_name = self.dir_name(),
+            id = self.id,
+            name = escape_xml(&self.name),
+            line_id = self.notes_field_line_id,
+            line_name = escape_xml(&self.notes_field_line_name),
+            nf_file = self.nf_file_name(),
+            st_file = self.st_file_name(),
+        )
+    }
+}
+
+/// Writes `<out_dir>/stageXXXXXX/` with the st/nf AFB files and `Stage.xml`,
+/// returning the created stage directory.
+pub fn build_stage_package(
+    stage: &StageBuilder,
+    info: &StageInfo,
+    out_dir: &Path,
+) -> Result<PathBuf> {
+    let stage_dir = out_dir.join(info.dir_name());
+    fs::create_dir_all(&stage_dir).map_err(Error::io(&stage_dir))?;
+
+    let mut writer = AtomicWriter::new();
+    writer.write(&stage_dir.join("Stage.xml"), info.to_xml().as_bytes())?;
+    stage.build_with(
+        writer,
+        &stage_dir.join(info.st_file_name()),
+        &stage_dir.join(info.nf_file_name()),
+    )?;
+    Ok(stage_dir)
+}
+
+pub fn music_dir_name(music_id: u32) -> String {
+    format!("music{:04}", music_id)
+}
+
+pub fn jacket_file_name(music_id: u32) -> String {
+    format!("CHU_UI_Jacket_{:04}.dds", music_id)
+}
+
+/// Converts `in_path` to `<out_dir>/musicXXXX/CHU_UI_Jacket_XXXX.dds` and, when `music_xml`
+/// is given, points its jacket entry at the new file. Returns the written jacket path.
+pub fn build_jacket_package(
+    in_path: &Path,
+    music_id: u32,
+    out_dir: &Path,
+    music_xml: Option<&Path>,
+) -> Result<PathBuf> {
+    build_jacket_package_with_options(
+        in_path,
+        music_id,
+        out_dir,
+        music_xml,
+        &DecodeOptions::default(),
+        &Progress::none(),
+    )
+}
+
+/// Like `build_jacket_package`, decoding the jacket with `decode` and reporting to `progress`.
+pub fn build_jacket_package_with_options(
+    in_path: &Path,
+    music_id: u32,
+    out_dir: &Path,
+    music_xml: Option<&Path>,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<PathBuf> {
+    let music_dir = out_dir.join(music_dir_name(music_id));
+    fs::create_dir_all(&music_dir).map_err(Error::io(&music_dir))?;
+
+    let jacket_path = music_dir.join(jacket_file_name(music_id));
+    let dds = jacket_dds(in_path, decode, progress)?;
+
+    let mut writer = AtomicWriter::new();
+    writer.write(&jacket_path, &dds)?;
+    if let Some(music_xml) = music_xml {
+        let patched = patched_music_xml(music_xml, music_id)?;
+        writer.write(music_xml, patched.as_bytes())?;
+    }
+    writer.commit()?;
+    Ok(jacket_path)
+}
+
+/// Sets the `<jaketFile>` path of an existing Music.xml (the game's own spelling),
+/// inserting the element when it is missing.
+pub fn patch_music_xml(music_xml: &Path, music_id: u32) -> Result<()> {
+    let patched = patched_music_xml(music_xml, music_id)?;
+    write_atomic(music_xml, patched.as_bytes())
+}
+
+fn patched_music_xml(music_xml: &Path, music_id: u32) -> Result<String> {
+    const OPEN_TAG: &str = "<jaketFile";
+    const CLOSE_TAG: &str = "</jaketFile>";
+    const ROOT_CLOSE_TAG: &str = "</MusicData>";
+
+    let xml = fs::read_to_string(music_xml).map_err(Error::io(music_xml))?;
+    let invalid = |offset, reason| Error::InvalidMusicXml {
+        path: music_xml.to_path_buf(),
+        offset,
+        reason,
+    };
+    let element = format!(
+        "<jaketFile>\n    <path>{}</path>\n  </jaketFile>",
+        escape_xml(&jacket_file_name(music_id))
+    );
+
+    // The tag name has to end after `jaketFile`, so longer names like `<jaketFileX>` are skipped.
+    let open_tag = xml
+        .match_indices(OPEN_TAG)
+        .map(|(start, _)| start)
+        .find(|&start| {
+            xml[start + OPEN_TAG.len()..]
+                .starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace())
+        });
+    let patched = if let Some(start) = open_tag {
+        let tag_end = xml[start..]
+            .find('>')
+            .map(|pos| start + pos + 1)
+            .ok_or_else(|| invalid(start, "unterminated <jaketFile> element"))?;
+        let end = if xml[..tag_end].ends_with("/>") {
+            tag_end
+        } else {
+            xml[tag_end..]
+                .find(CLOSE_TAG)
+                .map(|pos| tag_end + pos + CLOSE_TAG.len())
+                .ok_or_else(|| invalid(tag_end, "missing </jaketFile>"))?
+        };
+        format!("{}{}{}", &xml[..start], element, &xml[end..])
+    } else if let Some(root_end) = xml.rfind(ROOT_CLOSE_TAG) {
+        format!("{}  {}\n{}", &xml[..root_end], element, &xml[root_end..])
+    } else {
+        return Err(invalid(xml.len(), "no <MusicData> root found"));
+    };
+
+    Ok(patched)
+}
+
+pub(crate) fn escape_xml(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,303 @@
+use crate::img::atomic::write_atomic;
+use crate::img::cache::{CacheKey, cached};
+use crate::img::decode::{DecodeOptions, open_image};
+use crate::img::error::{Error, Result};
+use crate::img::progress::{Progress, Step};
+use crate::img::utils::{apply_alpha_mode, compress_image, fit_image, save_dds_blob};
+use directxtex::DXGI_FORMAT;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{LazyLock, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitMode {
+    /// Resize to the exact target size, ignoring the aspect ratio.
+    Stretch,
+    /// Scale to fit inside the target and pad the rest with transparent pixels.
+    Contain,
+    /// Scale to cover the target and crop the overflow.
+    Cover,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlphaMode {
+    Keep,
+    /// Force every pixel to be fully opaque.
+    Opaque,
+    Premultiply,
+}
+
+/// Target size, DXGI format, fit/alpha handling and decode options of a named conversion.
+#[derive(Debug, Clone, Copy)]
+pub struct Preset {
+    pub width: u32,
+    pub height: u32,
+    pub format: DXGI_FORMAT,
+    pub fit: FitMode,
+    pub alpha: AlphaMode,
+    pub decode: DecodeOptions,
+}
+
+impl Preset {
+    pub const fn new(width: u32, height: u32, format: DXGI_FORMAT) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            fit: FitMode::Stretch,
+            alpha: AlphaMode::Keep,
+            decode: DecodeOptions::new(),
+        }
+    }
+
+    pub const fn fit(mut self, fit: FitMode) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    pub const fn alpha(mut self, alpha: AlphaMode) -> Self {
+        self.alpha = alpha;
+        self
+    }
+
+    pub const fn decode(mut self, decode: DecodeOptions) -> Self {
+        self.decode = decode;
+        self
+    }
+}
+
+/// Named presets, starting with the built-in asset types.
+pub struct PresetRegistry {
+    presets: HashMap<String, Preset>,
+}
+
+impl Default for PresetRegistry {
+    fn default() -> Self {
+        const BC1: DXGI_FORMAT = DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM;
+        const BC3: DXGI_FORMAT = DXGI_FORMAT::DXGI_FORMAT_BC3_UNORM;
+
+        let mut registry = Self::empty();
+        registry.register(
+            "background",
+            Preset::new(1920, 1080, BC1).alpha(AlphaMode::Opaque),
+        );
+        registry.register(
+            "jacket",
+            Preset::new(300, 300, BC1).alpha(AlphaMode::Opaque),
+        );
+        registry.register("nameplate", Preset::new(576, 228, BC3).fit(FitMode::Cover));
+        registry.register(
+            "character_icon",
+            Preset::new(128, 128, BC3).fit(FitMode::Cover),
+        );
+        registry.register("map_icon", Preset::new(128, 128, BC3).fit(FitMode::Contain));
+        registry.register(
+            "system_voice",
+            Preset::new(256, 256, BC3).fit(FitMode::Contain),
+        );
+        registry.register("trophy", Preset::new(256, 256, BC3).fit(FitMode::Contain));
+        registry
+    }
+}
+
+impl PresetRegistry {
+    pub fn empty() -> Self {
+        Self {
+            presets: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: impl Into<String>, preset: Preset) {
+        self.presets.insert(name.into(), preset);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Preset> {
+        self.presets.get(name).copied()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.presets.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Parses preset definitions, one per line:
+    /// `name width height format [fit] [alpha] [flags...]`, e.g. `nameplate 576 228 BC3 cover keep`.
+    /// The flags `ignore-orientation`, `ignore-color-profile` and `no-dither` may appear anywhere
+    /// after the format and turn off the matching decode step.
+    /// Blank lines and lines starting with `#` are ignored. Existing names are overwritten.
+    /// Nothing is registered unless every line parses.
+    pub fn load_str(&mut self, definitions: &str) -> Result<()> {
+        let mut parsed = Vec::new();
+        for (line_no, line) in definitions.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            parsed.push(
+                parse_preset_line(line).map_err(|message| Error::InvalidPreset {
+                    line: line_no + 1,
+                    message,
+                })?,
+            );
+        }
+        for (name, preset) in parsed {
+            self.register(name, preset);
+        }
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        let definitions = std::fs::read_to_string(path).map_err(Error::io(path))?;
+        self.load_str(&definitions)
+    }
+}
+
+static PRESETS: LazyLock<RwLock<PresetRegistry>> =
+    LazyLock::new(|| RwLock::new(PresetRegistry::default()));
+
+/// Adds the presets defined in `path` to the global registry used by `convert_preset`.
+pub fn load_presets(path: &Path) -> Result<()> {
+    let mut registry = PRESETS.write().map_err(|_| Error::Poisoned)?;
+    registry.load_file(path)
+}
+
+pub fn get_preset(name: &str) -> Result<Preset> {
+    let registry = PRESETS.read().map_err(|_| Error::Poisoned)?;
+    registry
+        .get(name)
+        .ok_or_else(|| Error::UnknownPreset(name.to_string()))
+}
+
+pub fn convert_with_preset(
+    in_path: &Path,
+    preset: &Preset,
+    progress: &Progress,
+) -> Result<directxtex::ScratchImage> {
+    progress.checkpoint(Step::Decoding, 0.0)?;
+    let img = open_image(in_path, &preset.decode)?;
+
+    progress.checkpoint(Step::Resizing, 0.25)?;
+    let mut rgba_image = fit_image(
+        img,
+        preset.width,
+        preset.height,
+        preset.fit,
+        &preset.decode,
+        &progress.range(0.25, 0.5),
+    )?;
+    apply_alpha_mode(&mut rgba_image, preset.alpha);
+
+    progress.checkpoint(Step::Compressing, 0.5)?;
+    let mut pixel_vec = rgba_image.into_raw();
+    let dds = compress_image(
+        preset.width,
+        preset.height,
+        preset.format,
+        &mut pixel_vec,
+        &progress.range(0.5, 1.0),
+    )?;
+    progress.checkpoint(Step::Compressing, 1.0)?;
+    Ok(dds)
+}
+
+pub fn convert_preset(name: &str, in_path: &Path, out_path: &Path) -> Result<()> {
+    convert_preset_with_progress(name, in_path, out_path, &Progress::none())
+}
+
+pub fn convert_preset_with_progress(
+    name: &str,
+    in_path: &Path,
+    out_path: &Path,
+    progress: &Progress,
+) -> Result<()> {
+    convert_preset_file(&get_preset(name)?, in_path, out_path, progress)
+}
+
+/// Converts `in_path` with an unregistered (or adjusted) `preset` and writes the DDS to `out_path`.
+pub fn convert_preset_file(
+    preset: &Preset,
+    in_path: &Path,
+    out_path: &Path,
+    progress: &Progress,
+) -> Result<()> {
+    let dds = cached(
+        || {
+            let mut key = CacheKey::new("preset");
+            key.input(in_path)?.param(preset);
+            Ok(key)
+        },
+        || {
+            let dds = convert_with_preset(in_path, preset, &progress.range(0.0, 0.9))?;
+            Ok(save_dds_blob(dds)?.buffer().to_vec())
+        },
+    )?;
+    progress.checkpoint(Step::Writing, 0.9)?;
+    write_atomic(out_path, &dds)?;
+    progress.report(Step::Writing, 1.0);
+    Ok(())
+}
+
+fn parse_preset_line(line: &str) -> Result<(String, Preset), String> {
+    let mut decode = DecodeOptions::new();
+    let mut fields: Vec<&str> = Vec::new();
+    for field in line.split_whitespace() {
+        match field.to_ascii_lowercase().as_str() {
+            "ignore-orientation" if fields.len() >= 4 => decode.orientation = false,
+            "ignore-color-profile" if fields.len() >= 4 => decode.color_profile = false,
+            "no-dither" if fields.len() >= 4 => decode.dither = false,
+            _ => fields.push(field),
+        }
+    }
+    if !(4..=6).contains(&fields.len()) {
+        return Err(format!(
+            "Expected `name width height format [fit] [alpha] [flags...]`, got `{}`",
+            line
+        ));
+    }
+
+    let width: u32 = fields[1]
+        .parse()
+        .map_err(|_| format!("Invalid width: {}", fields[1]))?;
+    let height: u32 = fields[2]
+        .parse()
+        .map_err(|_| format!("Invalid height: {}", fields[2]))?;
+    if width == 0 || height == 0 {
+        return Err(Error::InvalidDimensions { width, height }.to_string());
+    }
+
+    let format = parse_format(fields[3]).map_err(|err| err.to_string())?;
+    let mut preset = Preset::new(width, height, format).decode(decode);
+    if let Some(fit) = fields.get(4) {
+        preset.fit = match fit.to_ascii_lowercase().as_str() {
+            "stretch" => FitMode::Stretch,
+            "contain" => FitMode::Contain,
+            "cover" => FitMode::Cover,
+            _ => return Err(format!("Unknown fit mode: {}", fit)),
+        };
+    }
+    if let Some(alpha) = fields.get(5) {
+        preset.alpha = match alpha.to_ascii_lowercase().as_str() {
+            "keep" => AlphaMode::Keep,
+            "opaque" => AlphaMode::Opaque,
+            "premultiply" => AlphaMode::Premultiply,
+            _ => return Err(format!("Unknown alpha mode: {}", alpha)),
+        };
+    }
+
+    Ok((fields[0].to_string(), preset))
+}
+
+/// Parses a format name used in preset definitions: `RGBA8`, `BC1`, `BC2`, `BC3` or `BC7`.
+pub fn parse_format(name: &str) -> Result<DXGI_FORMAT> {
+    let format = match name.to_ascii_uppercase().as_str() {
+        "RGBA8" => DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM,
+        "BC1" => DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM,
+        "BC2" => DXGI_FORMAT::DXGI_FORMAT_BC2_UNORM,
+        "BC3" => DXGI_FORMAT::DXGI_FORMAT_BC3_UNORM,
+        "BC7" => DXGI_FORMAT::DXGI_FORMAT_BC7_UNORM,
+        _ => return Err(Error::UnsupportedFormat(name.to_string())),
+    };
+    Ok(format)
+}
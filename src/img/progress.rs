@@ -0,0 +1,108 @@
+use crate::img::error::{Error, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Decoding,
+    Resizing,
+    Compressing,
+    Writing,
+}
+
+impl Step {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Step::Decoding => "decoding",
+            Step::Resizing => "resizing",
+            Step::Compressing => "compressing",
+            Step::Writing => "writing",
+        }
+    }
+}
+
+/// Shared flag that a running conversion polls at every `Progress::checkpoint`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+type Callback = dyn Fn(Step, f32) + Send + Sync;
+
+/// Reports the current step and the overall fraction (0.0-1.0) of an operation.
+/// Nested steps report through `range`, which maps their own 0.0-1.0 onto a slice of the parent.
+#[derive(Clone)]
+pub struct Progress {
+    callback: Option<Arc<Callback>>,
+    cancel: Option<CancelToken>,
+    start: f32,
+    end: f32,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl Progress {
+    pub fn none() -> Self {
+        Self {
+            callback: None,
+            cancel: None,
+            start: 0.0,
+            end: 1.0,
+        }
+    }
+
+    pub fn new(callback: impl Fn(Step, f32) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Some(Arc::new(callback)),
+            ..Self::none()
+        }
+    }
+
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Reports progress, then fails with `Error::Cancelled` if cancellation was requested.
+    /// Conversions call this before each step, so nothing has been written when it fails.
+    pub fn checkpoint(&self, step: Step, fraction: f32) -> Result<()> {
+        self.report(step, fraction);
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    pub fn report(&self, step: Step, fraction: f32) {
+        if let Some(callback) = &self.callback {
+            let fraction = fraction.clamp(0.0, 1.0);
+            callback(step, self.start + (self.end - self.start) * fraction);
+        }
+    }
+
+    pub fn range(&self, from: f32, to: f32) -> Self {
+        let span = self.end - self.start;
+        Self {
+            callback: self.callback.clone(),
+            cancel: self.cancel.clone(),
+            start: self.start + span * from.clamp(0.0, 1.0),
+            end: self.start + span * to.clamp(0.0, 1.0),
+        }
+    }
+}
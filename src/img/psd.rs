@@ -0,0 +1,261 @@
+use crate::img::error::{Error, Result};
+use image::{DynamicImage, ImageBuffer, Pixel};
+use std::path::Path;
+
+const ICC_PROFILE_RESOURCE: u16 = 0x040f;
+/// Largest width or height Photoshop allows in a PSD and in a PSB document.
+const MAX_PSD_SIDE: u32 = 30_000;
+const MAX_PSB_SIDE: u32 = 300_000;
+
+/// The flattened composite of a PSD or PSB document.
+pub(crate) struct Psd {
+    pub image: DynamicImage,
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+/// Decodes the composite image that Photoshop stores after the layers. Only files saved with
+/// "Maximize Compatibility" have a meaningful composite. Grayscale, indexed and RGB documents
+/// at 8 or 16 bits per channel are supported; a channel after the colour channels is alpha.
+pub(crate) fn decode_psd(data: &[u8], path: &Path) -> Result<Psd> {
+    let mut r = Reader { data, pos: 0, path };
+    if r.bytes(4)? != b"8BPS" {
+        return Err(r.invalid(0, "missing PSD signature"));
+    }
+    let psb = match r.u16()? {
+        1 => false,
+        2 => true,
+        _ => return Err(r.invalid(4, "unknown version")),
+    };
+    r.bytes(6)?;
+    let channels = r.u16()? as usize;
+    let height = r.u32()?;
+    let width = r.u32()?;
+    let depth = r.u16()?;
+    let mode = r.u16()?;
+    if !(1..=56).contains(&channels) {
+        return Err(r.invalid(12, "invalid channel count"));
+    }
+    let max_side = if psb { MAX_PSB_SIDE } else { MAX_PSD_SIDE };
+    if width == 0 || height == 0 || width > max_side || height > max_side {
+        return Err(Error::InvalidDimensions { width, height });
+    }
+    let color_channels = match mode {
+        1 | 2 => 1,
+        3 => 3,
+        _ => return Err(Error::UnsupportedFormat(format!("PSD color mode {}", mode))),
+    };
+    if !matches!(depth, 8 | 16) || (mode == 2 && depth != 8) {
+        return Err(Error::UnsupportedFormat(format!("{}-bit PSD", depth)));
+    }
+    if channels < color_channels {
+        return Err(r.invalid(12, "too few channels for the color mode"));
+    }
+
+    let color_mode_data = r.section()?;
+    let icc_profile = find_resource(r.section()?, ICC_PROFILE_RESOURCE).map(<[u8]>::to_vec);
+    let layers_len = if psb { r.u64()? } else { r.u32()? as u64 };
+    r.bytes(usize::try_from(layers_len).unwrap_or(usize::MAX))?;
+
+    // Indexed documents keep transparency in a resource rather than a channel.
+    let used = if mode == 2 {
+        1
+    } else {
+        channels.min(color_channels + 1)
+    };
+    let too_large = || Error::InvalidPsd {
+        path: path.to_path_buf(),
+        offset: 14,
+        reason: "dimensions exceed the file size",
+    };
+    let row_len = (width as usize)
+        .checked_mul((depth / 8) as usize)
+        .ok_or_else(too_large)?;
+    let plane_len = row_len.checked_mul(height as usize).ok_or_else(too_large)?;
+    // PackBits expands at most 64 times, so larger planes can't be backed by the file.
+    if plane_len.checked_mul(used).ok_or_else(too_large)? / 64 > data.len() {
+        return Err(too_large());
+    }
+
+    let planes: Vec<Vec<u8>> = match r.u16()? {
+        0 => (0..used)
+            .map(|_| r.bytes(plane_len).map(<[u8]>::to_vec))
+            .collect::<Result<_>>()?,
+        1 => {
+            // The byte count of every row of every channel, 4 bytes each in PSB files.
+            let entry_len = if psb { 4 } else { 2 };
+            let table_len = channels
+                .checked_mul(height as usize)
+                .and_then(|rows| rows.checked_mul(entry_len))
+                .ok_or_else(too_large)?;
+            let row_sizes: Vec<usize> = r
+                .bytes(table_len)?
+                .chunks_exact(entry_len)
+                .map(|entry| {
+                    entry
+                        .iter()
+                        .fold(0, |size, &byte| size << 8 | byte as usize)
+                })
+                .collect();
+            let mut planes = Vec::with_capacity(used);
+            for channel_rows in row_sizes.chunks(height as usize).take(used) {
+                let mut plane = Vec::with_capacity(plane_len);
+                for &size in channel_rows {
+                    let offset = r.pos;
+                    if !unpack_bits(r.bytes(size)?, &mut plane, row_len) {
+                        return Err(r.invalid(offset, "invalid PackBits row"));
+                    }
+                }
+                planes.push(plane);
+            }
+            planes
+        }
+        compression => {
+            return Err(Error::UnsupportedFormat(format!(
+                "PSD compression {}",
+                compression
+            )));
+        }
+    };
+
+    let image = match (depth, used) {
+        _ if mode == 2 => {
+            if color_mode_data.len() < 768 {
+                return Err(r.invalid(26, "missing indexed color table"));
+            }
+            let pixels = planes[0]
+                .iter()
+                .flat_map(|&i| [0, 256, 512].map(|c| color_mode_data[c + i as usize]))
+                .collect();
+            DynamicImage::ImageRgb8(interleaved(width, height, pixels))
+        }
+        (8, 1) => DynamicImage::ImageLuma8(interleaved(width, height, interleave_u8(&planes))),
+        (8, 2) => DynamicImage::ImageLumaA8(interleaved(width, height, interleave_u8(&planes))),
+        (8, 3) => DynamicImage::ImageRgb8(interleaved(width, height, interleave_u8(&planes))),
+        (8, _) => DynamicImage::ImageRgba8(interleaved(width, height, interleave_u8(&planes))),
+        (_, 1) => DynamicImage::ImageLuma16(interleaved(width, height, interleave_u16(&planes))),
+        (_, 2) => DynamicImage::ImageLumaA16(interleaved(width, height, interleave_u16(&planes))),
+        (_, 3) => DynamicImage::ImageRgb16(interleaved(width, height, interleave_u16(&planes))),
+        (_, _) => DynamicImage::ImageRgba16(interleaved(width, height, interleave_u16(&planes))),
+    };
+    Ok(Psd { image, icc_profile })
+}
+
+fn interleaved<P: Pixel>(
+    width: u32,
+    height: u32,
+    pixels: Vec<P::Subpixel>,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    ImageBuffer::from_raw(width, height, pixels).expect("planes match the image dimensions")
+}
+
+fn interleave_u8(planes: &[Vec<u8>]) -> Vec<u8> {
+    (0..planes[0].len())
+        .flat_map(|i| planes.iter().map(move |plane| plane[i]))
+        .collect()
+}
+
+fn interleave_u16(planes: &[Vec<u8>]) -> Vec<u16> {
+    (0..planes[0].len() / 2)
+        .flat_map(|i| {
+            planes
+                .iter()
+                .map(move |plane| u16::from_be_bytes([plane[2 * i], plane[2 * i + 1]]))
+        })
+        .collect()
+}
+
+/// Appends one PackBits-compressed row of exactly `len` bytes to `out`.
+fn unpack_bits(mut src: &[u8], out: &mut Vec<u8>, len: usize) -> bool {
+    let end = out.len() + len;
+    while out.len() < end {
+        let Some((&header, rest)) = src.split_first() else {
+            return false;
+        };
+        let header = header as i8;
+        src = rest;
+        if header >= 0 {
+            let count = header as usize + 1;
+            let Some(literal) = src.get(..count) else {
+                return false;
+            };
+            out.extend_from_slice(literal);
+            src = &src[count..];
+        } else if header != -128 {
+            let Some((&value, rest)) = src.split_first() else {
+                return false;
+            };
+            out.resize(out.len() + (1 - header as isize) as usize, value);
+            src = rest;
+        }
+    }
+    out.len() == end
+}
+
+/// Returns the data of the image resource `id`, if present.
+fn find_resource(mut resources: &[u8], id: u16) -> Option<&[u8]> {
+    while resources.len() >= 12 && resources.starts_with(b"8BIM") {
+        let resource_id = u16::from_be_bytes([resources[4], resources[5]]);
+        // The Pascal-string name, including its length byte, is padded to an even size.
+        let name_len = (resources[6] as usize + 1).next_multiple_of(2);
+        let size_offset = 6 + name_len;
+        let size = u32::from_be_bytes(
+            resources
+                .get(size_offset..size_offset + 4)?
+                .try_into()
+                .ok()?,
+        ) as usize;
+        let data = resources.get(size_offset + 4..size_offset + 4 + size)?;
+        if resource_id == id {
+            return Some(data);
+        }
+        resources = resources.get(size_offset + 4 + size.next_multiple_of(2)..)?;
+    }
+    None
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    path: &'a Path,
+}
+
+impl<'a> Reader<'a> {
+    fn invalid(&self, offset: usize, reason: &'static str) -> Error {
+        Error::InvalidPsd {
+            path: self.path.to_path_buf(),
+            offset,
+            reason,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len());
+        let Some(end) = end else {
+            return Err(self.invalid(self.data.len(), "truncated file"));
+        };
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a section prefixed with its 32-bit length.
+    fn section(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+}
@@ -0,0 +1,106 @@
+use crate::img::decode::DecodeOptions;
+use image::{ColorType, DynamicImage, Rgba, RgbaImage};
+use log::debug;
+
+/// Operator mapping linear HDR values onto the 0-1 display range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapping {
+    /// Clip everything above 1.0.
+    Clamp,
+    /// `c / (1 + c)`: keeps every highlight but flattens contrast.
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+impl ToneMapping {
+    fn apply(self, c: f32) -> f32 {
+        let c = c.max(0.0);
+        match self {
+            ToneMapping::Clamp => c,
+            ToneMapping::Reinhard => c / (1.0 + c),
+            ToneMapping::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+        }
+        .min(1.0)
+    }
+}
+
+/// 4x4 Bayer matrix, as rounding thresholds in 0-1.
+const BAYER: [[f32; 4]; 4] = {
+    const RANKS: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+    let mut thresholds = [[0.0; 4]; 4];
+    let mut y = 0;
+    while y < 4 {
+        let mut x = 0;
+        while x < 4 {
+            thresholds[y][x] = (RANKS[y][x] as f32 + 0.5) / 16.0;
+            x += 1;
+        }
+        y += 1;
+    }
+    thresholds
+};
+
+/// Reduces `img` to 8 bits per channel. 8-bit images are passed through; 16-bit images are
+/// quantised directly and float (HDR) images are tone mapped from linear light to sRGB first.
+pub(crate) fn to_rgba8(img: DynamicImage, options: &DecodeOptions) -> RgbaImage {
+    let hdr = match img.color() {
+        ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8 => {
+            return img.into_rgba8();
+        }
+        ColorType::Rgb32F | ColorType::Rgba32F => true,
+        _ => false,
+    };
+
+    let mut pixels = img.into_rgba32f();
+    if hdr {
+        debug!(
+            "Tone mapping with {:?} at {:+} EV",
+            options.tone_mapping, options.exposure
+        );
+        let scale = options.exposure.exp2();
+        for pixel in pixels.pixels_mut() {
+            for c in pixel.0.iter_mut().take(3) {
+                *c = linear_to_srgb(options.tone_mapping.apply(*c * scale));
+            }
+        }
+    }
+
+    debug!(
+        "Quantising {}x{} to 8 bits{}",
+        pixels.width(),
+        pixels.height(),
+        if options.dither {
+            " with dithering"
+        } else {
+            ""
+        }
+    );
+    RgbaImage::from_fn(pixels.width(), pixels.height(), |x, y| {
+        let [r, g, b, a] = pixels.get_pixel(x, y).0;
+        let threshold = if options.dither {
+            BAYER[y as usize % 4][x as usize % 4]
+        } else {
+            0.5
+        };
+        // Alpha is never dithered; noise there would break BC1 punch-through and BC3 blocks.
+        Rgba([
+            quantize(r, threshold),
+            quantize(g, threshold),
+            quantize(b, threshold),
+            quantize(a, 0.5),
+        ])
+    })
+}
+
+fn quantize(value: f32, threshold: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0 + threshold).min(255.0) as u8
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
@@ -0,0 +1,222 @@
+use crate::img::assets::{FX_DUMMY, NF_DUMMY, ST_CHUNKS, ST_DUMMY};
+use crate::img::atomic::AtomicWriter;
+use crate::img::cache::{CacheKey, active_cache};
+use crate::img::convert::{convert_dds_with_options, convert_fx_animation, convert_fx_slots};
+use crate::img::decode::DecodeOptions;
+use crate::img::error::{Error, Result};
+use crate::img::locate::replace_chunks;
+use crate::img::progress::{CancelToken, Progress, Step};
+use crate::img::utils::save_dds_blob;
+use directxtex::DXGI_FORMAT;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const FX_SLOTS: usize = 4;
+
+/// Builds the st and nf AFB files of a stage from a background and up to `FX_SLOTS` FX images.
+#[derive(Clone)]
+pub struct StageBuilder {
+    background: Option<PathBuf>,
+    fx: [Option<PathBuf>; FX_SLOTS],
+    /// The first slot passed to `fx` that doesn't exist, reported by `build_to`.
+    invalid_fx_slot: Option<usize>,
+    fx_animation: Option<PathBuf>,
+    format: DXGI_FORMAT,
+    decode: DecodeOptions,
+    progress: Progress,
+}
+
+impl Default for StageBuilder {
+    fn default() -> Self {
+        Self {
+            background: None,
+            fx: Default::default(),
+            invalid_fx_slot: None,
+            fx_animation: None,
+            format: DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM,
+            decode: DecodeOptions::default(),
+            progress: Progress::none(),
+        }
+    }
+}
+
+impl StageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn background(mut self, path: impl Into<PathBuf>) -> Self {
+        self.background = Some(path.into());
+        self
+    }
+
+    /// Sets the FX image for `slot` (0-3), drawn in that slot's tile. Tiles of empty slots stay
+    /// transparent. Other slots make `build_to` fail with `Error::FxSlotOutOfRange`.
+    pub fn fx(mut self, slot: usize, path: impl Into<PathBuf>) -> Self {
+        match self.fx.get_mut(slot) {
+            Some(fx) => *fx = Some(path.into()),
+            None => {
+                self.invalid_fx_slot.get_or_insert(slot);
+            }
+        }
+        self
+    }
+
+    /// Fills the FX tiles with the first frames of the animation at `path`. Takes precedence
+    /// over images set with `fx`.
+    pub fn fx_animation(mut self, path: impl Into<PathBuf>) -> Self {
+        self.fx_animation = Some(path.into());
+        self
+    }
+
+    /// Sets the DXGI format used for the background texture.
+    pub fn format(mut self, format: DXGI_FORMAT) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets how the background and FX images are decoded.
+    pub fn decode(mut self, decode: DecodeOptions) -> Self {
+        self.decode = decode;
+        self
+    }
+
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// A copy of the builder whose progress polls `token` instead of its own cancel token.
+    pub(crate) fn with_cancel_token(&self, token: CancelToken) -> Self {
+        let mut builder = self.clone();
+        builder.progress = builder.progress.cancel_token(token);
+        builder
+    }
+
+    /// Writes the st and nf AFB files. Either both are replaced or, on failure or
+    /// cancellation, neither is.
+    pub fn build_to(&self, st_out_path: &Path, nf_out_path: &Path) -> Result<()> {
+        self.build_with(AtomicWriter::new(), st_out_path, nf_out_path)
+    }
+
+    /// Like `build_to`, but commits the stage files together with whatever `writer` already holds.
+    pub(crate) fn build_with(
+        &self,
+        mut writer: AtomicWriter,
+        st_out_path: &Path,
+        nf_out_path: &Path,
+    ) -> Result<()> {
+        self.validate()?;
+        let Some(cache) = active_cache()? else {
+            self.write_to(&mut writer, st_out_path, nf_out_path)?;
+            writer.commit()?;
+            self.progress.report(Step::Writing, 1.0);
+            return Ok(());
+        };
+
+        let key = self.cache_key()?;
+        // The nf file never changes, so only st is cached.
+        if let Some((mut cached, cached_path)) = cache.open(&key) {
+            self.progress.checkpoint(Step::Writing, 0.85)?;
+            let mut st_file = writer.create(st_out_path)?;
+            io::copy(&mut cached, &mut st_file).map_err(Error::io(&cached_path))?;
+            drop(st_file);
+            writer.write(nf_out_path, NF_DUMMY)?;
+            writer.commit()?;
+        } else {
+            self.write_to(&mut writer, st_out_path, nf_out_path)?;
+            writer.commit()?;
+            cache.put_file(&key, st_out_path);
+        }
+        self.progress.report(Step::Writing, 1.0);
+        Ok(())
+    }
+
+    /// Checks the options that don't need any file access, returning the background path.
+    fn validate(&self) -> Result<&Path> {
+        let Some(bg_in_path) = self.background.as_deref() else {
+            return Err(Error::MissingBackground);
+        };
+        if let Some(slot) = self.invalid_fx_slot {
+            return Err(Error::FxSlotOutOfRange {
+                slot,
+                max: FX_SLOTS - 1,
+            });
+        }
+        Ok(bg_in_path)
+    }
+
+    fn cache_key(&self) -> Result<CacheKey> {
+        let bg_in_path = self.background.as_deref().ok_or(Error::MissingBackground)?;
+        let mut key = CacheKey::new("stage");
+        key.input(bg_in_path)?
+            .param(&self.format)
+            .param(&self.decode);
+        match self.fx_animation.as_deref() {
+            Some(animation) => {
+                key.param(&"fx_animation").input(animation)?;
+            }
+            None => {
+                for fx in &self.fx {
+                    match fx {
+                        Some(path) => key.input(path)?,
+                        None => key.param(&"empty"),
+                    };
+                }
+            }
+        }
+        Ok(key)
+    }
+
+    fn write_to(
+        &self,
+        writer: &mut AtomicWriter,
+        st_out_path: &Path,
+        nf_out_path: &Path,
+    ) -> Result<()> {
+        let bg_in_path = self.validate()?;
+
+        let bg_progress = self.progress.range(0.0, 0.55);
+        let bg_dds = save_dds_blob(convert_dds_with_options(
+            bg_in_path,
+            1920,
+            1080,
+            self.format,
+            &self.decode,
+            &bg_progress,
+        )?)?;
+        let fx_in_paths: Vec<Option<&Path>> = self.fx.iter().map(|p| p.as_deref()).collect();
+        let fx_progress = self.progress.range(0.55, 0.85);
+        let fx_dds = if let Some(animation) = self.fx_animation.as_deref() {
+            Some(save_dds_blob(convert_fx_animation(
+                animation,
+                &self.decode,
+                &fx_progress,
+            )?)?)
+        } else if fx_in_paths.iter().any(Option::is_some) {
+            Some(save_dds_blob(convert_fx_slots(
+                &fx_in_paths,
+                &self.decode,
+                &fx_progress,
+            )?)?)
+        } else {
+            None
+        };
+
+        let bg_buffer = bg_dds.buffer();
+        let fx_buffer = fx_dds.as_ref().map(|d| d.buffer()).or(Some(FX_DUMMY));
+
+        self.progress.checkpoint(Step::Writing, 0.85)?;
+        let replacements = &[Some(bg_buffer), fx_buffer];
+        let mut st_file = BufWriter::new(writer.create(st_out_path)?);
+        replace_chunks(
+            ST_DUMMY,
+            &mut st_file,
+            st_out_path,
+            &ST_CHUNKS,
+            replacements,
+        )?;
+        st_file.flush().map_err(Error::io(st_out_path))?;
+        writer.write(nf_out_path, NF_DUMMY)
+    }
+}
@@ -2,16 +2,42 @@
 
 #[cfg(test)]
 mod test {
-    use crate::img::convert::{convert_dds, convert_stage};
+    use crate::error::ErrorCode;
+    use crate::img::convert::{convert_dds, convert_stage, fx_canvas};
     use crate::img::utils::*;
-    use crate::img::{convert_fx, extract_afb};
+    use crate::img::{
+        CancelToken, DecodeOptions, Error, FitMode, Job, PresetRegistry, Progress, StageBuilder,
+        StageInfo, Step, build_jacket_package, build_stage_package, convert_fx, convert_preset,
+        extract_afb, get_preset, inspect_afb, patch_music_xml, read_dds_info, repack_afb,
+        run_batch,
+    };
     use anyhow::Result;
     use directxtex::DXGI_FORMAT;
+    use image::{Rgba, RgbaImage};
     use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    fn get_temp_image(_dir: &Path, _width: u32, _height: u32) -> PathBuf {
-        let random_num = rand::random_range(1..=4);
-        Path::new("tests").join(format!("{}.jpg", random_num))
+    /// Writes a `width`x`height` gradient PNG to `dir`. Every call gets its own file, so tests
+    /// running in parallel never read an image another one is still writing.
+    fn get_temp_image(dir: &Path, width: u32, height: u32) -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        std::fs::create_dir_all(dir).expect("create the test output directory");
+        let id = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("input_{}x{}_{}.png", width, height, id));
+        RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([x as u8, y as u8, (x ^ y) as u8, 255])
+        })
+        .save(&path)
+        .expect("write a test image");
+        path
+    }
+
+    /// Writes an AFB archive holding two DDS chunks to `dir` as `test.afb`.
+    fn get_temp_afb(dir: &Path) -> PathBuf {
+        std::fs::create_dir_all(dir).expect("create the test output directory");
+        let path = dir.join("test.afb");
+        std::fs::write(&path, crate::img::assets::ST_DUMMY).expect("write a test archive");
+        path
     }
 
     #[test]
@@ -116,20 +142,47 @@ mod test {
         let temp_dir = Path::new("test_assets/output");
         _ = std::fs::create_dir(temp_dir);
 
-        let afb_path = Path::new("test_assets/test.afb");
+        let afb_path = get_temp_afb(&temp_dir.join("afb"));
         let out_folder = temp_dir.to_str().unwrap();
-        extract_afb(afb_path, out_folder)?;
+        extract_afb(&afb_path, out_folder)?;
         assert!(temp_dir.join("test_0001.dds").exists());
         assert!(temp_dir.join("test_0002.dds").exists());
         Ok(())
     }
 
+    #[test]
+    fn test_repack_afb() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output/repack");
+        std::fs::create_dir_all(temp_dir)?;
+
+        let afb_path = get_temp_afb(temp_dir);
+        let afb_path = afb_path.as_path();
+        extract_afb(afb_path, temp_dir.to_str().unwrap())?;
+        let second = temp_dir.join("test_0002.dds");
+        let info = read_dds_info(&second)?;
+        assert!(info.width > 0 && info.height > 0);
+
+        let out_path = temp_dir.join("repacked.afb");
+        repack_afb(afb_path, &out_path, &[(0, second.as_path())])?;
+        let chunks = inspect_afb(&out_path)?;
+        let data = std::fs::read(&out_path)?;
+        assert_eq!(
+            &data[chunks[0].start..chunks[0].end],
+            std::fs::read(&second)?
+        );
+
+        let err = repack_afb(afb_path, &out_path, &[(99, second.as_path())]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        Ok(())
+    }
+
     #[test]
     fn test_convert_stage() -> Result<()> {
         let temp_dir = Path::new("test_assets/output");
         _ = std::fs::create_dir(temp_dir);
 
-        let bg_image = Path::new("test_assets/bg.png");
+        let bg_image = get_temp_image(temp_dir, 640, 360);
+        let bg_image = bg_image.as_path();
 
         let st_output = temp_dir.join("output_st.afb");
         let nf_output = temp_dir.join("output_nf.afb");
@@ -162,4 +215,1046 @@ mod test {
         );
         assert!(result.is_err(), "Should fail with invalid FX image");
     }
+
+    #[test]
+    fn test_stage_builder() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let st_output = temp_dir.join("builder_st.afb");
+        let nf_output = temp_dir.join("builder_nf.afb");
+
+        StageBuilder::new()
+            .background(get_temp_image(temp_dir, 640, 360))
+            .fx(1, get_temp_image(temp_dir, 256, 256))
+            .format(DXGI_FORMAT::DXGI_FORMAT_BC3_UNORM)
+            .build_to(&st_output, &nf_output)?;
+
+        assert!(st_output.exists());
+        assert!(nf_output.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_builder_invalid_options() {
+        let temp_dir = Path::new("test_assets/output");
+        let output_path = temp_dir.join("output.afb");
+
+        let result = StageBuilder::new().build_to(&output_path, &output_path);
+        assert!(
+            matches!(result, Err(Error::MissingBackground)),
+            "Should fail without a background image"
+        );
+
+        let result = StageBuilder::new()
+            .background(get_temp_image(temp_dir, 640, 360))
+            .fx(4, get_temp_image(temp_dir, 256, 256))
+            .build_to(&output_path, &output_path);
+        assert!(
+            matches!(result, Err(Error::FxSlotOutOfRange { slot: 4, max: 3 })),
+            "Should fail with an out of range FX slot"
+        );
+    }
+
+    #[test]
+    fn test_fx_tiles_follow_slots() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let red = temp_dir.join("fx_slot_red.png");
+        image::RgbaImage::from_pixel(256, 256, image::Rgba([255, 0, 0, 255])).save(&red)?;
+
+        let canvas = fx_canvas(
+            &[None, Some(&red), None, Some(&red)],
+            &DecodeOptions::default(),
+            &Progress::none(),
+        )?;
+        assert_eq!(canvas.get_pixel(10, 10).0[3], 0);
+        assert_eq!(canvas.get_pixel(266, 10).0, [255, 0, 0, 255]);
+        assert_eq!(canvas.get_pixel(10, 266).0[3], 0);
+        assert_eq!(canvas.get_pixel(266, 266).0, [255, 0, 0, 255]);
+
+        // Out-of-range slots are reported by `build_to` without allocating anything per slot.
+        let output_path = temp_dir.join("output.afb");
+        let result = StageBuilder::new()
+            .background(&red)
+            .fx(usize::MAX, &red)
+            .fx(4, &red)
+            .build_to(&output_path, &output_path);
+        assert!(
+            matches!(
+                result,
+                Err(Error::FxSlotOutOfRange {
+                    slot: usize::MAX,
+                    max: 3
+                })
+            ),
+            "Should reject the first slot past the last tile"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_stage_package() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let stage = StageBuilder::new().background(get_temp_image(temp_dir, 640, 360));
+        let info = StageInfo::new(1234, "Penguin & Friends");
+        let stage_dir = build_stage_package(&stage, &info, temp_dir)?;
+
+        assert_eq!(stage_dir, temp_dir.join("stage001234"));
+        assert!(stage_dir.join("st_001234.afb").exists());
+        assert!(stage_dir.join("nf_001234.afb").exists());
+
+        let xml = std::fs::read_to_string(stage_dir.join("Stage.xml"))?;
+        assert!(xml.contains("<str>Penguin &amp; Friends</str>"));
+        assert!(xml.contains("<path>st_001234.afb</path>"));
+        assert!(xml.contains("<path>nf_001234.afb</path>"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_jacket_package() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let music_xml = temp_dir.join("Music.xml");
+        std::fs::write(
+            &music_xml,
+            "<MusicData>\n  <jaketFile>\n    <path>old.dds</path>\n  </jaketFile>\n</MusicData>\n",
+        )?;
+
+        let img_path = get_temp_image(temp_dir, 300, 300);
+        let jacket = build_jacket_package(&img_path, 42, temp_dir, Some(&music_xml))?;
+
+        assert_eq!(jacket, temp_dir.join("music0042/CHU_UI_Jacket_0042.dds"));
+        assert!(jacket.exists());
+
+        let xml = std::fs::read_to_string(&music_xml)?;
+        assert!(xml.contains("<path>CHU_UI_Jacket_0042.dds</path>"));
+        assert!(!xml.contains("old.dds"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_music_xml_inserts_missing_element() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let music_xml = temp_dir.join("Music_no_jacket.xml");
+        std::fs::write(&music_xml, "<MusicData>\n  <jaketFile />\n</MusicData>\n")?;
+        patch_music_xml(&music_xml, 7)?;
+        let xml = std::fs::read_to_string(&music_xml)?;
+        assert!(xml.contains("<path>CHU_UI_Jacket_0007.dds</path>"));
+
+        std::fs::write(&music_xml, "<MusicData>\n</MusicData>\n")?;
+        patch_music_xml(&music_xml, 7)?;
+        let xml = std::fs::read_to_string(&music_xml)?;
+        assert!(xml.contains("<path>CHU_UI_Jacket_0007.dds</path>"));
+        assert!(xml.trim_end().ends_with("</MusicData>"));
+
+        // Only the whole tag name matches.
+        std::fs::write(
+            &music_xml,
+            "<MusicData>\n  <jaketFileX>keep</jaketFileX>\n  <jaketFile\n/>\n</MusicData>\n",
+        )?;
+        patch_music_xml(&music_xml, 7)?;
+        let xml = std::fs::read_to_string(&music_xml)?;
+        assert!(xml.contains("<jaketFileX>keep</jaketFileX>"));
+        assert!(xml.contains("<path>CHU_UI_Jacket_0007.dds</path>"));
+        assert!(!xml.contains("<jaketFile\n/>"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_exif_orientation() -> Result<()> {
+        use crate::img::decode::open_image;
+        use image::codecs::jpeg::JpegEncoder;
+
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir_all(temp_dir);
+        let img_path = temp_dir.join("exif_rotate_90.jpg");
+
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg).encode_image(&image::RgbImage::new(16, 8))?;
+        // APP1 Exif segment holding a big-endian TIFF with Orientation (0x0112) = 6.
+        #[rustfmt::skip]
+        let exif: &[u8] = &[
+            0xFF, 0xE1, 0x00, 0x22, b'E', b'x', b'i', b'f', 0x00, 0x00,
+            b'M', b'M', 0x00, 0x2A, 0x00, 0x00, 0x00, 0x08,
+            0x00, 0x01,
+            0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        jpeg.splice(2..2, exif.iter().copied());
+        std::fs::write(&img_path, &jpeg)?;
+
+        let rotated = open_image(&img_path, &DecodeOptions::new())?;
+        assert_eq!((rotated.width(), rotated.height()), (8, 16));
+
+        let unrotated = open_image(&img_path, &DecodeOptions::new().orientation(false))?;
+        assert_eq!((unrotated.width(), unrotated.height()), (16, 8));
+        Ok(())
+    }
+
+    #[test]
+    fn test_preset_registry_load_str() -> Result<()> {
+        let mut registry = PresetRegistry::default();
+        let trophy = registry.get("trophy").expect("default trophy preset");
+        assert_eq!((trophy.width, trophy.height), (256, 256));
+
+        registry.load_str(
+            "# custom presets\n\
+             trophy 400 64 BC3 contain premultiply\n\
+             \n\
+             jacket 512 512 BC7\n",
+        )?;
+
+        let trophy = registry.get("trophy").expect("trophy preset");
+        assert_eq!((trophy.width, trophy.height), (400, 64));
+        assert_eq!(trophy.fit, FitMode::Contain);
+
+        let jacket = registry.get("jacket").expect("jacket preset");
+        assert_eq!((jacket.width, jacket.height), (512, 512));
+
+        assert!(registry.load_str("broken 0 64 BC3").is_err());
+        assert!(registry.load_str("broken 64 64 BC9").is_err());
+        assert!(registry.load_str("broken 64 64 BC3 squash").is_err());
+
+        registry.load_str("icon 64 64 BC3 cover ignore-orientation no-dither")?;
+        let icon = registry.get("icon").expect("icon preset");
+        assert_eq!(icon.fit, FitMode::Cover);
+        assert!(!icon.decode.orientation && !icon.decode.dither);
+        assert!(icon.decode.color_profile);
+        assert!(
+            registry
+                .load_str("broken ignore-orientation 64 64 BC3")
+                .is_err()
+        );
+
+        assert!(
+            registry
+                .load_str("partial 64 64 BC3\nbroken 0 64 BC3")
+                .is_err()
+        );
+        assert!(
+            registry.get("partial").is_none(),
+            "A failed load registers nothing"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_preset() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let img_path = get_temp_image(temp_dir, 100, 100);
+        let out_path = temp_dir.join("output_nameplate.dds");
+        convert_preset("nameplate", &img_path, &out_path)?;
+        assert!(out_path.exists());
+
+        assert!(convert_preset("no_such_preset", &img_path, &out_path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_batch_reports_each_job() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let jobs = [
+            Job::Jacket {
+                in_path: get_temp_image(temp_dir, 300, 300),
+                out_path: temp_dir.join("batch_jk.dds"),
+                decode: DecodeOptions::new(),
+            },
+            Job::Jacket {
+                in_path: temp_dir.join("nonexistent.jpg"),
+                out_path: temp_dir.join("batch_missing.dds"),
+                decode: DecodeOptions::new(),
+            },
+            Job::Stage {
+                stage: StageBuilder::new().background(get_temp_image(temp_dir, 640, 360)),
+                st_out_path: temp_dir.join("batch_st.afb"),
+                nf_out_path: temp_dir.join("batch_nf.afb"),
+            },
+            Job::Preset {
+                preset: get_preset("map_icon")?,
+                in_path: get_temp_image(temp_dir, 128, 128),
+                out_path: temp_dir.join("batch_map_icon.dds"),
+            },
+        ];
+
+        let results = run_batch(&jobs, 2)?;
+        assert_eq!(results.len(), jobs.len());
+        assert!(results[0].is_ok());
+        assert!(
+            results[1].is_err(),
+            "Missing input should fail only its own job"
+        );
+        assert!(results[2].is_ok());
+        assert!(results[3].is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let temp_dir = Path::new("test_assets/output");
+        let img_path = get_temp_image(temp_dir, 100, 100);
+
+        let err = convert_dds(&img_path, 0, 100, DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidDimensions {
+                width: 0,
+                height: 100
+            }
+        ));
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+
+        let err = is_valid_image(Path::new("nonexistent_file.png")).unwrap_err();
+        assert!(
+            matches!(&err, Error::Io { path, .. } if path == Path::new("nonexistent_file.png"))
+        );
+        assert_eq!(err.code(), ErrorCode::Io);
+
+        let no_chunks = temp_dir.join("no_chunks.bin");
+        std::fs::write(&no_chunks, b"nothing to extract").unwrap();
+        let err = extract_afb(&no_chunks, temp_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(&err, Error::NoChunks { path } if *path == no_chunks));
+        assert_eq!(err.code(), ErrorCode::ContainerFormat);
+
+        let err = StageBuilder::new()
+            .build_to(&temp_dir.join("a.afb"), &temp_dir.join("b.afb"))
+            .unwrap_err();
+        assert!(matches!(err, Error::MissingBackground));
+        assert_eq!(ErrorCode::of(&err.into()), ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_error_code_keeps_message() {
+        use crate::error::WithCode;
+        use anyhow::Context;
+
+        let err = Err::<(), _>(std::io::Error::other("disk on fire"))
+            .context("Failed to write out.dds")
+            .code(ErrorCode::Compression)
+            .unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::Compression);
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to write out.dds: disk on fire"
+        );
+        assert_eq!(err.chain().count(), 2);
+
+        let err = (|| -> anyhow::Result<()> {
+            crate::bail_code!(ErrorCode::InvalidArgument, "width must be positive");
+        })()
+        .context("Failed to convert jacket")
+        .unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::InvalidArgument);
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to convert jacket: width must be positive"
+        );
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_decode_options_reject_non_finite_exposure() {
+        use crate::api::{FRAME_INDEX, decode_options_from_raw};
+        use crate::img::DecodeOptions;
+
+        for exposure in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let err = decode_options_from_raw(DecodeOptions::new(), 0, 0, exposure, FRAME_INDEX, 0)
+                .unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::InvalidArgument);
+        }
+        assert!(decode_options_from_raw(DecodeOptions::new(), 0, 0, -2.5, FRAME_INDEX, 0).is_ok());
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_decode_options_from_raw_keep_base() -> Result<()> {
+        use crate::api::{FRAME_TIME, INPUT_NO_DITHER, decode_options_from_raw};
+        use crate::img::{DecodeOptions, FrameSelection, ToneMapping};
+        use std::time::Duration;
+
+        let mut base = DecodeOptions::new().orientation(false).exposure(1.0);
+        base.tone_mapping = ToneMapping::Aces;
+        let options = decode_options_from_raw(base, INPUT_NO_DITHER, 0, 0.5, FRAME_TIME, 250)?;
+        assert!(!options.orientation);
+        assert!(options.color_profile);
+        assert!(!options.dither);
+        assert_eq!(options.tone_mapping, ToneMapping::Aces);
+        assert_eq!(options.exposure, 1.5);
+        assert_eq!(
+            options.frame,
+            FrameSelection::Time(Duration::from_millis(250))
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_log_callback_can_unregister_itself() {
+        use crate::api::set_log_callback;
+        use std::ffi::{c_char, c_int, c_void};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        unsafe extern "C" fn unregister(
+            _level: c_int,
+            _target: *const c_char,
+            _message: *const c_char,
+            _user_data: *mut c_void,
+        ) {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            set_log_callback(None, std::ptr::null_mut(), 0);
+        }
+
+        set_log_callback(Some(unregister), std::ptr::null_mut(), 1);
+        log::error!("unregisters the callback from inside it");
+        assert!(CALLS.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn test_stage_builder_progress() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        StageBuilder::new()
+            .background(get_temp_image(temp_dir, 640, 360))
+            .fx(0, get_temp_image(temp_dir, 256, 256))
+            .progress(Progress::new(move |step, fraction| {
+                sink.lock().unwrap().push((step, fraction));
+            }))
+            .build_to(
+                &temp_dir.join("progress_st.afb"),
+                &temp_dir.join("progress_nf.afb"),
+            )?;
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.first(), Some(&(Step::Decoding, 0.0)));
+        assert_eq!(reports.last(), Some(&(Step::Writing, 1.0)));
+        assert!(reports.windows(2).all(|w| w[0].1 <= w[1].1));
+        for step in [Step::Decoding, Step::Resizing, Step::Compressing] {
+            assert!(reports.iter().any(|(s, _)| *s == step));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_builder_cancelled() {
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+
+        let st_output = temp_dir.join("cancelled_st.afb");
+        let nf_output = temp_dir.join("cancelled_nf.afb");
+        _ = std::fs::remove_file(&st_output);
+        _ = std::fs::remove_file(&nf_output);
+
+        let token = CancelToken::new();
+        token.cancel();
+        let err = StageBuilder::new()
+            .background(get_temp_image(temp_dir, 640, 360))
+            .progress(Progress::none().cancel_token(token))
+            .build_to(&st_output, &nf_output)
+            .unwrap_err();
+
+        assert_eq!(err.code(), ErrorCode::Cancelled);
+        assert!(!st_output.exists());
+        assert!(!nf_output.exists());
+    }
+
+    #[test]
+    fn test_cancel_after_resize() -> Result<()> {
+        use crate::img::convert::convert_dds_with_options;
+        use std::sync::{Arc, Mutex};
+
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+        let in_path = temp_dir.join("cancel_resize.png");
+        image::RgbaImage::from_pixel(640, 360, image::Rgba([40, 80, 120, 255])).save(&in_path)?;
+
+        let token = CancelToken::new();
+        let cancel = token.clone();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let progress = Progress::new(move |step, fraction| {
+            let mut reports = sink.lock().unwrap();
+            reports.push((step, fraction));
+            if reports.iter().filter(|(s, _)| *s == Step::Resizing).count() == 2 {
+                cancel.cancel();
+            }
+        })
+        .cancel_token(token);
+
+        let result = convert_dds_with_options(
+            &in_path,
+            1920,
+            1080,
+            DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM,
+            &DecodeOptions::default(),
+            &progress,
+        );
+        assert!(matches!(result, Err(Error::Cancelled)));
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.last(), Some(&(Step::Resizing, 0.5)));
+        assert!(reports.iter().all(|(step, _)| *step != Step::Compressing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_keeps_hdr_values() -> Result<()> {
+        use crate::img::utils::resize_if_needed;
+        use image::{DynamicImage, Rgb, Rgb32FImage};
+
+        let source = DynamicImage::ImageRgb32F(Rgb32FImage::from_fn(64, 32, |x, _| {
+            Rgb([if x < 32 { 8.0 } else { 0.5 }, 2.0, 0.0])
+        }));
+        let resized = resize_if_needed(source, 16, 8, &Progress::none())?.into_rgba32f();
+        assert_eq!(resized.dimensions(), (16, 8));
+        let pixel = resized.get_pixel(2, 4).0;
+        assert!((pixel[0] - 8.0).abs() < 0.01, "Red was {}", pixel[0]);
+        assert!((pixel[1] - 2.0).abs() < 0.01, "Green was {}", pixel[1]);
+        assert!((pixel[3] - 1.0).abs() < 0.01, "Alpha was {}", pixel[3]);
+
+        let black = DynamicImage::ImageRgb32F(Rgb32FImage::new(8, 8));
+        let resized = resize_if_needed(black, 4, 4, &Progress::none())?.into_rgba32f();
+        assert!(resized.pixels().all(|p| p.0[..3] == [0.0; 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_batch_cancelled() -> Result<()> {
+        use crate::img::run_batch_with_cancel;
+
+        let temp_dir = Path::new("test_assets/output");
+        _ = std::fs::create_dir(temp_dir);
+        let out_path = temp_dir.join("batch_cancelled.dds");
+        _ = std::fs::remove_file(&out_path);
+
+        let token = CancelToken::new();
+        token.cancel();
+        let jobs = [Job::Preset {
+            preset: get_preset("jacket")?,
+            in_path: get_temp_image(temp_dir, 300, 300),
+            out_path: out_path.clone(),
+        }];
+        let results = run_batch_with_cancel(&jobs, 1, &token)?;
+
+        assert!(matches!(results[0], Err(Error::Cancelled)));
+        assert!(!out_path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_builder_failure_keeps_existing_outputs() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output/atomic");
+        _ = std::fs::remove_dir_all(temp_dir);
+        std::fs::create_dir_all(temp_dir)?;
+
+        let background = get_temp_image(Path::new("test_assets/output"), 640, 360);
+        let st_output = temp_dir.join("st.afb");
+        std::fs::write(&st_output, b"previous")?;
+        let result = StageBuilder::new()
+            .background(background)
+            .build_to(&st_output, &temp_dir.join("missing").join("nf.afb"));
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&st_output)?, b"previous");
+        assert_eq!(std::fs::read_dir(temp_dir)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_writer_restores_targets() -> Result<()> {
+        use crate::img::atomic::AtomicWriter;
+
+        let temp_dir = Path::new("test_assets/output/atomic_writer");
+        _ = std::fs::remove_dir_all(temp_dir);
+        std::fs::create_dir_all(temp_dir)?;
+        let first = temp_dir.join("first.bin");
+        let second = temp_dir.join("second.bin");
+        std::fs::write(&first, b"first")?;
+        std::fs::write(&second, b"second")?;
+        let file_names = || -> Result<Vec<String>> {
+            let mut names = std::fs::read_dir(temp_dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+
+        // The second `create` fails, so the writer is dropped before committing anything.
+        let mut writer = AtomicWriter::new();
+        writer.write(&first, b"new first")?;
+        assert!(
+            writer
+                .create(&temp_dir.join("missing").join("x.bin"))
+                .is_err()
+        );
+        drop(writer);
+        assert_eq!(std::fs::read(&first)?, b"first");
+        assert_eq!(file_names()?, ["first.bin", "second.bin"]);
+
+        // The second rename fails after the first target was already replaced.
+        let mut writer = AtomicWriter::new();
+        writer.write(&first, b"new first")?;
+        writer.write(&second, b"new second")?;
+        let second_tmp = file_names()?
+            .into_iter()
+            .find(|name| name.starts_with(".second.bin.") && name.ends_with(".tmp"))
+            .unwrap();
+        std::fs::remove_file(temp_dir.join(second_tmp))?;
+        assert!(writer.commit().is_err());
+
+        assert_eq!(std::fs::read(&first)?, b"first");
+        assert_eq!(std::fs::read(&second)?, b"second");
+        assert_eq!(file_names()?, ["first.bin", "second.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities() {
+        use crate::capabilities::*;
+
+        let bits = capabilities();
+        assert_ne!(bits & CAP_INPUT_PNG, 0);
+        assert_ne!(bits & CAP_DXGI_BC1_UNORM, 0);
+        assert_ne!(bits & CAP_AFB_STAGE, 0);
+        assert_eq!(bits & (CAP_AUDIO | CAP_VIDEO), 0);
+
+        let names = capability_names();
+        assert!(names.contains(&"input:png"));
+        assert!(names.contains(&"afb:extract"));
+        assert_eq!(names.len() as u32, bits.count_ones());
+    }
+
+    #[test]
+    fn test_decode_color_profile() -> Result<()> {
+        use crate::img::DecodeOptions;
+        use crate::img::decode::open_image;
+        use image::ImageEncoder;
+        use image::codecs::png::PngEncoder;
+
+        let temp_dir = Path::new("test_assets/output");
+        std::fs::create_dir_all(temp_dir)?;
+        let png_path = temp_dir.join("display_p3.png");
+        let mut encoder = PngEncoder::new(std::fs::File::create(&png_path)?);
+        encoder.set_icc_profile(moxcms::ColorProfile::new_display_p3().encode()?)?;
+        encoder.write_image(&[40, 180, 90], 1, 1, image::ExtendedColorType::Rgb8)?;
+
+        let as_stored = open_image(&png_path, &DecodeOptions::new().color_profile(false))?;
+        assert_eq!(as_stored.to_rgb8().as_raw(), &[40, 180, 90]);
+        let converted = open_image(&png_path, &DecodeOptions::default())?;
+        assert_ne!(converted.to_rgb8().as_raw(), &[40, 180, 90]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_high_bit_depth_quantisation() {
+        use crate::img::quantize::to_rgba8;
+        use crate::img::{DecodeOptions, ToneMapping};
+        use image::{DynamicImage, ImageBuffer, Rgb, Rgba};
+
+        // Halfway between 100 and 101 in 8 bits.
+        let flat = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            4,
+            4,
+            Rgba([25829, 25829, 25829, 65535]),
+        ));
+        let rounded = to_rgba8(flat.clone(), &DecodeOptions::new().dither(false));
+        assert!(rounded.pixels().all(|p| p.0 == [101, 101, 101, 255]));
+        let dithered = to_rgba8(flat, &DecodeOptions::new());
+        assert_eq!(dithered.pixels().filter(|p| p[0] == 101).count(), 8);
+        assert!(dithered.pixels().all(|p| p[0] >= 100 && p[3] == 255));
+
+        let hdr = DynamicImage::ImageRgb32F(ImageBuffer::from_pixel(1, 1, Rgb([4.0, 0.0, 0.0])));
+        let clamped = to_rgba8(
+            hdr.clone(),
+            &DecodeOptions::new().tone_mapping(ToneMapping::Clamp),
+        );
+        assert_eq!(clamped.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        let reinhard = to_rgba8(
+            hdr,
+            &DecodeOptions::new().tone_mapping(ToneMapping::Reinhard),
+        );
+        assert!((200..255).contains(&reinhard.get_pixel(0, 0)[0]));
+    }
+
+    #[test]
+    fn test_animated_frame_selection() -> Result<()> {
+        use crate::img::decode::{open_frames, open_image};
+        use crate::img::{DecodeOptions, FrameSelection};
+        use image::codecs::gif::GifEncoder;
+        use image::{Delay, Frame, Rgba, RgbaImage};
+        use std::time::Duration;
+
+        let temp_dir = Path::new("test_assets/output");
+        std::fs::create_dir_all(temp_dir)?;
+        let gif_path = temp_dir.join("animated.gif");
+        let mut encoder = GifEncoder::new(std::fs::File::create(&gif_path)?);
+        for red in [0, 200, 255] {
+            let buffer = RgbaImage::from_pixel(8, 8, Rgba([red, 0, 0, 255]));
+            let delay = Delay::from_numer_denom_ms(100, 1);
+            encoder.encode_frame(Frame::from_parts(buffer, 0, 0, delay))?;
+        }
+        drop(encoder);
+
+        let red_of = |frame| -> Result<u8> {
+            let options = DecodeOptions::new().frame(frame);
+            Ok(open_image(&gif_path, &options)?.to_rgba8().get_pixel(0, 0)[0])
+        };
+        assert!(red_of(FrameSelection::Index(0))? < 10);
+        assert!(red_of(FrameSelection::Time(Duration::from_millis(250)))? > 245);
+        assert!(red_of(FrameSelection::Time(Duration::from_secs(10)))? > 245);
+        assert!((190..=210).contains(&red_of(FrameSelection::Representative)?));
+        let err = open_image(
+            &gif_path,
+            &DecodeOptions::new().frame(FrameSelection::Index(3)),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::FrameOutOfRange { index: 3, max: 2 }));
+
+        assert_eq!(open_frames(&gif_path, &DecodeOptions::new())?.count(), 3);
+
+        let err = crate::img::convert_atlas(
+            &gif_path,
+            8192,
+            8,
+            DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM,
+            &DecodeOptions::new(),
+            &Progress::none(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidDimensions {
+                width: 24576,
+                height: 8
+            }
+        ));
+        for frame_height in [0, 16385] {
+            let err = crate::img::convert_atlas(
+                &gif_path,
+                8,
+                frame_height,
+                DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM,
+                &DecodeOptions::new(),
+                &Progress::none(),
+            )
+            .unwrap_err();
+            assert!(matches!(err, Error::InvalidDimensions { width: 8, .. }));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tga_and_psd_inputs() -> Result<()> {
+        use crate::img::DecodeOptions;
+        use crate::img::decode::open_image;
+        use crate::img::psd::decode_psd;
+        use image::ImageEncoder;
+        use image::codecs::tga::TgaEncoder;
+
+        let temp_dir = Path::new("test_assets/output");
+        std::fs::create_dir_all(temp_dir)?;
+
+        // TGA is recognised from its header, whatever the extension says.
+        let tga_path = temp_dir.join("mislabelled.png");
+        TgaEncoder::new(std::fs::File::create(&tga_path)?).write_image(
+            &[10; 2 * 2 * 4],
+            2,
+            2,
+            image::ExtendedColorType::Rgba8,
+        )?;
+        is_valid_image(&tga_path)?;
+        assert_eq!(open_image(&tga_path, &DecodeOptions::new())?.width(), 2);
+        let corrupt_path = temp_dir.join("corrupt.tga");
+        std::fs::write(&corrupt_path, b"not a targa file, just some text")?;
+        assert!(is_valid_image(&corrupt_path).is_err());
+
+        // A 2x1 RGBA document with an RLE composite.
+        let mut psd = b"8BPS\x00\x01\x00\x00\x00\x00\x00\x00\x00\x04".to_vec();
+        psd.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 8, 0, 3]);
+        psd.extend_from_slice(&[0; 12]);
+        psd.extend_from_slice(&[0, 1, 0, 3, 0, 2, 0, 3, 0, 2]);
+        psd.extend_from_slice(&[1, 255, 0, 0xff, 7, 1, 0, 255, 0xff, 255]);
+        let psd_path = temp_dir.join("layered.psd");
+        std::fs::write(&psd_path, psd)?;
+        is_valid_image(&psd_path)?;
+        let composite = open_image(&psd_path, &DecodeOptions::new())?.to_rgba8();
+        assert_eq!(composite.as_raw(), &[255, 7, 0, 255, 0, 7, 255, 255]);
+
+        // Sides above the PSD (30000) and PSB (300000) limits are rejected before anything is
+        // sized from them.
+        for (version, side) in [(1u8, 30_001u32), (2, 300_001), (2, 300_000)] {
+            let mut header = b"8BPS\x00".to_vec();
+            header.extend_from_slice(&[version, 0, 0, 0, 0, 0, 0, 0, 4]);
+            header.extend_from_slice(&side.to_be_bytes());
+            header.extend_from_slice(&side.to_be_bytes());
+            header.extend_from_slice(&[0, 8, 0, 3]);
+            // Empty colour mode data, resources and layers; the layer length is 64-bit in PSB.
+            header.resize(header.len() + if version == 1 { 12 } else { 16 }, 0);
+            header.extend_from_slice(&[0, 1]);
+            let err = decode_psd(&header, &psd_path)
+                .err()
+                .expect("oversized document");
+            if side == 300_000 {
+                assert!(matches!(err, Error::InvalidPsd { .. }), "{err}");
+            } else {
+                assert!(matches!(err, Error::InvalidDimensions { .. }), "{err}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversion_cache() -> Result<()> {
+        use crate::img::ConversionCache;
+        use crate::img::cache::CacheKey;
+        use std::time::{Duration, SystemTime};
+
+        let temp_dir = Path::new("test_assets/output/cache_test");
+        _ = std::fs::remove_dir_all(temp_dir);
+        let cache = ConversionCache::new(temp_dir.join("cache"))?;
+        let (a, b) = (temp_dir.join("a.bin"), temp_dir.join("b.bin"));
+        std::fs::write(&a, b"same bytes")?;
+        std::fs::write(&b, b"same bytes")?;
+
+        // Keys depend on the input contents and parameters, not on the paths.
+        let key = |path: &Path, format: DXGI_FORMAT| -> Result<CacheKey> {
+            let mut key = CacheKey::new("test");
+            key.input(path)?.param(&format);
+            Ok(key)
+        };
+        let bc1 = DXGI_FORMAT::DXGI_FORMAT_BC1_UNORM;
+        cache.put(&key(&a, bc1)?, b"converted");
+        assert_eq!(
+            cache.get(&key(&b, bc1)?).as_deref(),
+            Some(&b"converted"[..])
+        );
+
+        // Entries that can't be written, as in a shared read-only cache, are still hits. Only
+        // on Unix, where read-only files can still be removed by `prune` and the next run.
+        #[cfg(unix)]
+        {
+            let long_ago = SystemTime::UNIX_EPOCH + Duration::from_secs(86_400);
+            for entry in std::fs::read_dir(cache.dir())? {
+                let path = entry?.path();
+                std::fs::File::options()
+                    .append(true)
+                    .open(&path)?
+                    .set_modified(long_ago)?;
+                let mut permissions = std::fs::metadata(&path)?.permissions();
+                permissions.set_readonly(true);
+                std::fs::set_permissions(&path, permissions)?;
+            }
+            assert_eq!(
+                cache.get(&key(&a, bc1)?).as_deref(),
+                Some(&b"converted"[..])
+            );
+            // The hit was still marked as recently used through the read-only handle.
+            for entry in std::fs::read_dir(cache.dir())? {
+                assert!(entry?.metadata()?.modified()? > long_ago);
+            }
+        }
+        assert!(
+            cache
+                .get(&key(&a, DXGI_FORMAT::DXGI_FORMAT_BC3_UNORM)?)
+                .is_none()
+        );
+        std::fs::write(&b, b"other bytes")?;
+        assert!(cache.get(&key(&b, bc1)?).is_none());
+
+        cache.put(&key(&b, bc1)?, b"converted too");
+        // Temporaries of interrupted writes are removed once they are old enough that no
+        // write can still be in progress.
+        let entry_name = key(&b, bc1)?.hex();
+        let stale_tmp = cache.dir().join(format!(".{}.1.0.tmp", entry_name));
+        let fresh_tmp = cache.dir().join(format!(".{}.1.1.tmp", entry_name));
+        std::fs::write(&stale_tmp, b"partial")?;
+        std::fs::write(&fresh_tmp, b"partial")?;
+        std::fs::File::options()
+            .append(true)
+            .open(&stale_tmp)?
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))?;
+        let stats = cache.prune(u64::MAX, None)?;
+        assert_eq!((stats.removed_entries, stats.kept_entries), (0, 2));
+        assert!(!stale_tmp.exists());
+        assert!(fresh_tmp.exists());
+        std::fs::remove_file(&fresh_tmp)?;
+        let stats = cache.prune(0, None)?;
+        assert_eq!((stats.removed_entries, stats.removed_bytes), (2, 22));
+        assert!(cache.get(&key(&a, bc1)?).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversions_use_the_cache() -> Result<()> {
+        use crate::img::{convert_jk_file, set_cache_dir};
+
+        let temp_dir = Path::new("test_assets/output/cache_use");
+        _ = std::fs::remove_dir_all(temp_dir);
+        std::fs::create_dir_all(temp_dir)?;
+        let cache_dir = temp_dir.join("cache");
+        // Pixels no other test uses, so no other conversion shares these cache entries.
+        let in_path = temp_dir.join("input.png");
+        RgbaImage::from_fn(64, 48, |x, y| Rgba([x as u8 * 3, y as u8 * 5, 0x5a, 255]))
+            .save(&in_path)?;
+
+        type Conversion<'a> = Box<dyn Fn(&Path) -> Result<()> + 'a>;
+        let conversions: [(&str, Conversion); 3] = [
+            (
+                "jacket",
+                Box::new(|out: &Path| {
+                    convert_jk_file(&in_path, out, &DecodeOptions::new(), &Progress::none())?;
+                    Ok(())
+                }),
+            ),
+            (
+                "preset",
+                Box::new(|out: &Path| Ok(convert_preset("map_icon", &in_path, out)?)),
+            ),
+            (
+                "stage",
+                Box::new(|out: &Path| {
+                    let nf_path = temp_dir.join("cached_nf.afb");
+                    StageBuilder::new()
+                        .background(&in_path)
+                        .build_to(out, &nf_path)?;
+                    Ok(())
+                }),
+            ),
+        ];
+
+        set_cache_dir(Some(&cache_dir))?;
+        let result = conversions.iter().try_for_each(|(name, convert)| {
+            let out = |run: usize| temp_dir.join(format!("{}_{}.out", name, run));
+            convert(&out(1))?;
+            convert(&out(2))?;
+            let converted = std::fs::read(out(1))?;
+            assert_eq!(std::fs::read(out(2))?, converted, "{}", name);
+
+            // The entry holding the output is what the next conversion returns.
+            let mut entries = 0;
+            for entry in std::fs::read_dir(&cache_dir)? {
+                let path = entry?.path();
+                if std::fs::read(&path)? == converted {
+                    std::fs::write(&path, b"from the cache")?;
+                    entries += 1;
+                }
+            }
+            assert_eq!(entries, 1, "{}", name);
+            convert(&out(3))?;
+            assert_eq!(std::fs::read(out(3))?, b"from the cache", "{}", name);
+            Ok(())
+        });
+        set_cache_dir(None)?;
+        result
+    }
+
+    #[test]
+    fn test_locate_chunks() {
+        use crate::img::assets::{ST_CHUNKS, ST_DUMMY};
+        use crate::img::locate::{SignatureRegistry, locate_payloads};
+
+        let signatures = SignatureRegistry::default();
+        let ranges = |input: &[u8]| -> Vec<(usize, usize)> {
+            locate_payloads(input, &signatures)
+                .iter()
+                .map(|p| (p.start, p.end))
+                .collect()
+        };
+        assert_eq!(
+            ranges(b"xxDDS aaPOF0yyDDS bbDDS cc"),
+            [(2, 8), (14, 20), (20, 26)]
+        );
+        assert_eq!(ranges(ST_DUMMY), ST_CHUNKS);
+
+        // A GTF magic without a valid header inside a DDS doesn't end it early.
+        let mut input = b"FILSDDS ".to_vec();
+        input.extend_from_slice(&[0; 16]);
+        input.extend_from_slice(&[0x02, 0x02, 0x00, 0xff]);
+        input.extend_from_slice(&[7; 16]);
+        input.extend_from_slice(b"POF0");
+        assert_eq!(ranges(&input), [(4, 44)]);
+
+        // Without stop signs every chunk runs to the next header; this used to rescan the rest
+        // of the input for each one.
+        let input = b"DDS ".repeat(200_000);
+        let chunks = ranges(&input);
+        assert_eq!(chunks.len(), 200_000);
+        assert_eq!(chunks.last(), Some(&(input.len() - 4, input.len())));
+    }
+
+    #[test]
+    fn test_extract_mixed_payloads() -> Result<()> {
+        use crate::img::assets::NF_DUMMY;
+        use image::ImageEncoder;
+        use image::codecs::png::PngEncoder;
+
+        let temp_dir = Path::new("test_assets/output/payloads");
+        _ = std::fs::remove_dir_all(temp_dir);
+        std::fs::create_dir_all(temp_dir)?;
+
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png).write_image(&[1, 2, 3], 1, 1, image::ExtendedColorType::Rgb8)?;
+        let mut archive = b"FILS\0\0\0\0\x40\0\0\0".to_vec();
+        archive.resize(0x40, 0);
+        archive.extend_from_slice(b"DDS texturePOF0");
+        archive.extend_from_slice(&png);
+        // A GNF magic whose header doesn't hold up is not a payload.
+        archive.extend_from_slice(b"GNF junk");
+        archive.extend_from_slice(NF_DUMMY);
+        archive.extend_from_slice(b"trailing");
+        let archive_path = temp_dir.join("mixed.afb");
+        std::fs::write(&archive_path, &archive)?;
+
+        assert_eq!(extract_afb(&archive_path, temp_dir.to_str().unwrap())?, 3);
+        assert_eq!(
+            std::fs::read(temp_dir.join("mixed_0001.dds"))?,
+            b"DDS texture"
+        );
+        assert_eq!(std::fs::read(temp_dir.join("mixed_0002.png"))?, png);
+        assert_eq!(std::fs::read(temp_dir.join("mixed_0003.afb"))?, NF_DUMMY);
+        let manifest = std::fs::read_to_string(temp_dir.join("mixed_manifest.tsv"))?;
+        assert_eq!(manifest.lines().count(), 4);
+        assert!(manifest.contains(&format!(
+            "mixed_0002.png\tpng\t{}\t{}",
+            0x40 + 15,
+            png.len()
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_afb_streams_chunks() -> Result<()> {
+        let temp_dir = Path::new("test_assets/output/streamed");
+        _ = std::fs::remove_dir_all(temp_dir);
+        std::fs::create_dir_all(temp_dir)?;
+
+        let afb_path = temp_dir.join("bundle.afb");
+        let mut afb = b"AFB header".to_vec();
+        for i in 0..1000u32 {
+            afb.extend_from_slice(b"DDS ");
+            afb.extend_from_slice(&i.to_le_bytes());
+            afb.extend_from_slice(b"POF0 padding");
+        }
+        std::fs::write(&afb_path, &afb)?;
+
+        assert_eq!(extract_afb(&afb_path, temp_dir.to_str().unwrap())?, 1000);
+        let last = std::fs::read(temp_dir.join("bundle_1000.dds"))?;
+        assert_eq!(last, [b"DDS ".as_slice(), &999u32.to_le_bytes()].concat());
+        Ok(())
+    }
 }
@@ -1,70 +1,207 @@
-use anyhow::Result;
+use crate::img::atomic::write_atomic;
+use crate::img::decode::DecodeOptions;
+use crate::img::error::{Error, Result};
+use crate::img::format::detect_format;
+use crate::img::preset::{AlphaMode, FitMode};
+use crate::img::progress::{Progress, Step};
+use crate::img::quantize::to_rgba8;
 use directxtex::{
     Blob, CP_FLAGS_NONE, DDS_FLAGS, DXGI_FORMAT, Image, ScratchImage, TEX_COMPRESS_DEFAULT,
 };
 use image::imageops::FilterType;
-use std::io::Read;
+use image::{ColorType, DynamicImage, Rgba, Rgba32FImage, RgbaImage};
+use log::debug;
+use std::any::Any;
 use std::path::Path;
 
+/// Checks that `in_path` starts with the signature of a supported image format, or with a
+/// consistent TGA header.
 pub fn is_valid_image(in_path: &Path) -> Result<()> {
-    // https://docs.rs/image/latest/image/fn.guess_format.html
-    if in_path.extension().and_then(|s| s.to_str()) == Some("tga") {
-        return Ok(());
-    }
-
-    let mut file = std::fs::File::open(in_path)?;
-    let mut buffer = [0; 32];
-    file.read_exact(&mut buffer)?;
-
-    image::guess_format(&buffer)?;
+    detect_format(in_path)?;
     Ok(())
 }
 
+/// Rows encoded between two cancellation checks. A multiple of the 4-row block height, so each
+/// strip encodes exactly the blocks it would as part of the whole image.
+const COMPRESS_STRIP_ROWS: u32 = 64;
+
 pub(crate) fn compress_image(
     width: u32,
     height: u32,
     format: DXGI_FORMAT,
-    pixel_data: &mut Vec<u8>,
+    pixel_data: &mut [u8],
+    progress: &Progress,
 ) -> Result<ScratchImage> {
-    let image = Image {
+    let compression_error = |e| Error::Compression(format!("Failed to compress image: {}", e));
+    let row_pitch = width as usize * 4;
+    let strip = |top: u32, rows: u32| Image {
         width: width as usize,
-        height: height as usize,
+        height: rows as usize,
         format: DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM,
-        row_pitch: width as usize * 4,
-        slice_pitch: width as usize * height as usize * 4,
-        pixels: pixel_data.as_mut_ptr(),
+        row_pitch,
+        slice_pitch: row_pitch * rows as usize,
+        pixels: pixel_data[top as usize * row_pitch..].as_ptr().cast_mut(),
     };
 
-    if image.format != format {
-        image
+    debug!("Compressing {}x{} image to {:?}", width, height, format);
+    let mut scratch_image = ScratchImage::default();
+    if format == DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM {
+        scratch_image
+            .initialize_from_image(&strip(0, height), true, CP_FLAGS_NONE)
+            .map_err(compression_error)?;
+        return Ok(scratch_image);
+    }
+
+    scratch_image
+        .initialize_2d(format, width as usize, height as usize, 1, 1, CP_FLAGS_NONE)
+        .map_err(compression_error)?;
+    let mut offset = 0;
+    for top in (0..height).step_by(COMPRESS_STRIP_ROWS as usize) {
+        progress.checkpoint(Step::Compressing, top as f32 / height as f32)?;
+        let rows = COMPRESS_STRIP_ROWS.min(height - top);
+        let blocks = strip(top, rows)
             .compress(format, TEX_COMPRESS_DEFAULT, 0.5)
-            .map_err(|e| anyhow::anyhow!("Failed to compress image: {}", e))
-    } else {
-        let mut scratch_image = ScratchImage::default();
-        scratch_image.initialize_from_image(&image, true, CP_FLAGS_NONE)?;
-        Ok(scratch_image)
+            .map_err(compression_error)?;
+        let blocks = blocks.pixels();
+        scratch_image
+            .pixels_mut()
+            .get_mut(offset..offset + blocks.len())
+            .ok_or_else(|| Error::Compression("Compressed strips overflow the image".into()))?
+            .copy_from_slice(blocks);
+        offset += blocks.len();
     }
+    Ok(scratch_image)
 }
 
+/// Writes `img` to `out_path` as a DDS file.
 pub fn save_dds_file(img: ScratchImage, out_path: &Path) -> Result<()> {
-    let blob = img.save_dds(DDS_FLAGS::DDS_FLAGS_NONE)?;
-    std::fs::write(out_path, blob.buffer())?;
-    Ok(())
+    let blob = save_dds_blob(img)?;
+    write_atomic(out_path, blob.buffer())
 }
 
+/// Serializes `img` as an in-memory DDS file.
 pub fn save_dds_blob(img: ScratchImage) -> Result<Blob> {
     img.save_dds(DDS_FLAGS::DDS_FLAGS_NONE)
-        .map_err(|e| anyhow::anyhow!("Failed to save DDS blob: {}", e))
+        .map_err(|e| Error::Compression(format!("Failed to save DDS blob: {}", e)))
 }
 
 pub(crate) fn resize_if_needed(
-    img: image::DynamicImage,
+    img: DynamicImage,
     target_width: u32,
     target_height: u32,
-) -> image::DynamicImage {
+    progress: &Progress,
+) -> Result<DynamicImage> {
     if img.width() != target_width || img.height() != target_height {
-        img.resize_exact(target_width, target_height, FilterType::Lanczos3)
+        debug!(
+            "Resizing {}x{} to {}x{}",
+            img.width(),
+            img.height(),
+            target_width,
+            target_height
+        );
+        let img = resize_hdr(img, |img| {
+            img.resize_exact(target_width, target_height, FilterType::Lanczos3)
+        });
+        progress.checkpoint(Step::Resizing, 1.0)?;
+        Ok(img)
+    } else {
+        Ok(img)
+    }
+}
+
+/// Runs `resize` on `img`. `image` clamps float samples to 1.0 while resampling, so HDR images
+/// are scaled down by their brightest channel first and back up afterwards, keeping the values
+/// above 1.0 for tone mapping.
+fn resize_hdr(
+    img: DynamicImage,
+    resize: impl FnOnce(&DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    if !matches!(img.color(), ColorType::Rgb32F | ColorType::Rgba32F) {
+        return resize(&img);
+    }
+    let mut img = img.into_rgba32f();
+    let peak = img
+        .pixels()
+        .flat_map(|p| &p.0[..3])
+        .fold(0.0f32, |peak, &c| peak.max(c));
+    // `f32::max` skips NaN, and black images are left alone instead of dividing by zero.
+    if peak <= 1.0 {
+        return resize(&DynamicImage::ImageRgba32F(img));
+    }
+    let scale = |img: &mut Rgba32FImage, factor: f32| {
+        img.pixels_mut()
+            .for_each(|p| p.0[..3].iter_mut().for_each(|c| *c *= factor));
+    };
+    scale(&mut img, 1.0 / peak);
+    let mut img = resize(&DynamicImage::ImageRgba32F(img)).into_rgba32f();
+    scale(&mut img, peak);
+    DynamicImage::ImageRgba32F(img)
+}
+
+pub(crate) fn fit_image(
+    img: DynamicImage,
+    target_width: u32,
+    target_height: u32,
+    fit: FitMode,
+    decode: &DecodeOptions,
+    progress: &Progress,
+) -> Result<RgbaImage> {
+    debug!(
+        "Fitting {}x{} into {}x{} ({:?})",
+        img.width(),
+        img.height(),
+        target_width,
+        target_height,
+        fit
+    );
+    let img = match fit {
+        FitMode::Stretch => resize_if_needed(img, target_width, target_height, progress)?,
+        FitMode::Cover => {
+            if img.width() == target_width && img.height() == target_height {
+                img
+            } else {
+                let img = resize_hdr(img, |img| {
+                    img.resize_to_fill(target_width, target_height, FilterType::Lanczos3)
+                });
+                progress.checkpoint(Step::Resizing, 1.0)?;
+                img
+            }
+        }
+        FitMode::Contain => {
+            let scaled = resize_hdr(img, |img| {
+                img.resize(target_width, target_height, FilterType::Lanczos3)
+            });
+            progress.checkpoint(Step::Resizing, 1.0)?;
+            let scaled = to_rgba8(scaled, decode);
+            let mut canvas = RgbaImage::from_pixel(target_width, target_height, Rgba([0, 0, 0, 0]));
+            let x = (target_width - scaled.width()) / 2;
+            let y = (target_height - scaled.height()) / 2;
+            image::imageops::replace(&mut canvas, &scaled, x as i64, y as i64);
+            return Ok(canvas);
+        }
+    };
+    Ok(to_rgba8(img, decode))
+}
+
+pub(crate) fn apply_alpha_mode(img: &mut RgbaImage, alpha: AlphaMode) {
+    match alpha {
+        AlphaMode::Keep => {}
+        AlphaMode::Opaque => img.pixels_mut().for_each(|p| p[3] = 255),
+        AlphaMode::Premultiply => img.pixels_mut().for_each(|p| {
+            let a = p[3] as u16;
+            for c in p.0.iter_mut().take(3) {
+                *c = ((*c as u16 * a + 127) / 255) as u8;
+            }
+        }),
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
     } else {
-        img
+        "unknown panic payload"
     }
 }